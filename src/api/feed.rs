@@ -0,0 +1,354 @@
+use std::fmt::Write;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::SystemTime;
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse, web};
+use actix_web::http::header::{EntityTag, ETag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified};
+use chrono::{DateTime, SecondsFormat, Utc};
+use crate::definitions::{FeedPost, SiteInfo};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+// How many posts a single feed contains
+const FEED_LENGTH: i64 = 50;
+
+enum FeedFormat {
+    Rss,
+    Atom,
+}
+
+impl FeedFormat {
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "rss" => Some(FeedFormat::Rss),
+            "atom" => Some(FeedFormat::Atom),
+            _ => None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+}
+
+// Describes the feed itself, independent of the format it is rendered in
+struct FeedChannel {
+    title: String,
+    description: String,
+    site_url: String,
+    self_url: String,
+    // Not known for a feed without posts
+    updated: Option<DateTime<Utc>>,
+}
+
+// The feeds don't live under /api/v1 since feed readers expect them at the root of the site
+pub fn feed_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(feed_get)
+        .service(feed_tag_get)
+        .service(feed_project_get);
+}
+
+#[utoipa::path(tag = "feeds", responses((status = 200, content((String = "application/rss+xml"), (String = "application/atom+xml"))), (status = 304)))]
+#[get("/feed.{format}")]
+async fn feed_get(
+    req: HttpRequest,
+    path: web::Path<String>,
+    database_manager: web::Data<DatabaseManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+
+    let format = path.into_inner();
+    let title = site_info.title.clone();
+
+    serve_feed(&req, &format, title, None, None, &database_manager, &site_info).await
+}
+
+#[utoipa::path(tag = "feeds", responses((status = 200, content((String = "application/rss+xml"), (String = "application/atom+xml"))), (status = 304)))]
+#[get("/feed/tags/{tag}.{format}")]
+async fn feed_tag_get(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    database_manager: web::Data<DatabaseManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+
+    let (tag, format) = path.into_inner();
+    let title = format!("{} - #{}", site_info.title, tag);
+
+    serve_feed(&req, &format, title, Some(tag), None, &database_manager, &site_info).await
+}
+
+#[utoipa::path(tag = "feeds", responses((status = 200, content((String = "application/rss+xml"), (String = "application/atom+xml"))), (status = 304)))]
+#[get("/feed/projects/{project}.{format}")]
+async fn feed_project_get(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    database_manager: web::Data<DatabaseManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+
+    let (project, format) = path.into_inner();
+    let title = format!("{} - {}", site_info.title, project);
+
+    serve_feed(&req, &format, title, None, Some(project), &database_manager, &site_info).await
+}
+
+async fn serve_feed(
+    req: &HttpRequest,
+    format: &str,
+    title: String,
+    tag: Option<String>,
+    project: Option<String>,
+    database_manager: &DatabaseManager,
+    site_info: &SiteInfo) -> Result<HttpResponse, ApiError> {
+
+    let format = FeedFormat::from_extension(format).ok_or(ApiError::NotFound)?;
+
+    let posts = database_manager.fetch_published_posts(tag.clone(), project.clone(), FEED_LENGTH).await?;
+    let last_deletion = database_manager.fetch_last_post_deletion(tag, project).await?;
+
+    // Edits and deletions change the feed as well, not only new posts
+    let updated = posts.iter()
+        .map(|post| post.updated)
+        .chain(last_deletion)
+        .max();
+
+    let channel = FeedChannel {
+        description: format!("Latest posts from {}", title),
+        title,
+        site_url: site_info.url.clone(),
+        self_url: format!("{}{}", site_info.url, req.path()),
+        updated,
+    };
+
+    let body = match format {
+        FeedFormat::Rss => render_rss(&channel, &posts),
+        FeedFormat::Atom => render_atom(&channel, &posts),
+    };
+
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = EntityTag::new_strong(format!("{:x}", hasher.finish()));
+    // Round trip through HttpDate so we compare with the same precision the client got from us.
+    // A feed which never had posts has no date to tell, then only the ETag is sent.
+    let last_modified = channel.updated.map(|updated| HttpDate::from(SystemTime::from(updated)));
+
+    let not_modified = is_not_modified(req, &etag, last_modified);
+
+    let mut response = if not_modified { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+    response.insert_header(ETag(etag));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(LastModified(last_modified));
+    }
+
+    if not_modified {
+        return Ok(response.finish());
+    }
+
+    Ok(response
+        .content_type(format.content_type())
+        .body(body))
+}
+
+// If-None-Match takes precedence over If-Modified-Since, see RFC 9110 section 13.2.2
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag, last_modified: Option<HttpDate>) -> bool {
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        };
+    }
+
+    if let (Some(IfModifiedSince(since)), Some(last_modified)) = (req.get_header::<IfModifiedSince>(), last_modified) {
+        return SystemTime::from(last_modified) <= SystemTime::from(since);
+    }
+
+    false
+}
+
+fn post_link(channel: &FeedChannel, post: &FeedPost) -> String {
+    format!("{}/posts/{}", channel.site_url, post.id)
+}
+
+fn render_rss(channel: &FeedChannel, posts: &[FeedPost]) -> String {
+    let mut xml = String::new();
+
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+    xml.push_str("<channel>\n");
+    let _ = writeln!(xml, "<title>{}</title>", escape_xml(&channel.title));
+    let _ = writeln!(xml, "<link>{}</link>", escape_xml(&channel.site_url));
+    let _ = writeln!(xml, "<description>{}</description>", escape_xml(&channel.description));
+    let _ = writeln!(xml, "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>", escape_xml(&channel.self_url));
+    if let Some(updated) = channel.updated {
+        let _ = writeln!(xml, "<lastBuildDate>{}</lastBuildDate>", updated.to_rfc2822());
+    }
+
+    for post in posts {
+        let link = escape_xml(&post_link(channel, post));
+
+        xml.push_str("<item>\n");
+        let _ = writeln!(xml, "<title>{}</title>", escape_xml(&post.title));
+        let _ = writeln!(xml, "<link>{}</link>", link);
+        let _ = writeln!(xml, "<guid isPermaLink=\"true\">{}</guid>", link);
+        let _ = writeln!(xml, "<pubDate>{}</pubDate>", post.posted.to_rfc2822());
+        for author_name in &post.author_names {
+            // <author> has to be an email address in RSS, so we use the dublin core creator instead
+            let _ = writeln!(xml, "<dc:creator>{}</dc:creator>", escape_xml(author_name));
+        }
+        if let Some(summary) = &post.summary {
+            let _ = writeln!(xml, "<description>{}</description>", escape_xml(summary));
+        }
+        for tag in &post.tags {
+            let _ = writeln!(xml, "<category>{}</category>", escape_xml(tag));
+        }
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n");
+    xml.push_str("</rss>\n");
+    xml
+}
+
+fn render_atom(channel: &FeedChannel, posts: &[FeedPost]) -> String {
+    let mut xml = String::new();
+
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(xml, "<id>{}</id>", escape_xml(&channel.self_url));
+    let _ = writeln!(xml, "<title>{}</title>", escape_xml(&channel.title));
+    let _ = writeln!(xml, "<subtitle>{}</subtitle>", escape_xml(&channel.description));
+    let _ = writeln!(xml, "<link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>", escape_xml(&channel.self_url));
+    let _ = writeln!(xml, "<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>", escape_xml(&channel.site_url));
+    // Atom requires the date even without entries, then the feed was last updated when it was generated
+    let updated = channel.updated.unwrap_or_else(Utc::now);
+    let _ = writeln!(xml, "<updated>{}</updated>", updated.to_rfc3339_opts(SecondsFormat::Secs, true));
+    // Atom requires an author for every entry, entries without one inherit this
+    let _ = writeln!(xml, "<author><name>{}</name></author>", escape_xml(&channel.title));
+
+    for post in posts {
+        let link = escape_xml(&post_link(channel, post));
+
+        xml.push_str("<entry>\n");
+        let _ = writeln!(xml, "<id>{}</id>", link);
+        let _ = writeln!(xml, "<title>{}</title>", escape_xml(&post.title));
+        let _ = writeln!(xml, "<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>", link);
+        let _ = writeln!(xml, "<published>{}</published>", post.posted.to_rfc3339_opts(SecondsFormat::Secs, true));
+        let _ = writeln!(xml, "<updated>{}</updated>", post.updated.to_rfc3339_opts(SecondsFormat::Secs, true));
+        for author_name in &post.author_names {
+            let _ = writeln!(xml, "<author><name>{}</name></author>", escape_xml(author_name));
+        }
+        if let Some(summary) = &post.summary {
+            let _ = writeln!(xml, "<summary>{}</summary>", escape_xml(summary));
+        }
+        for tag in &post.tags {
+            let _ = writeln!(xml, "<category term=\"{}\"/>", escape_xml(tag));
+        }
+        xml.push_str("</entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// Escapes text so it can be used as XML character data or inside an attribute value
+///
+/// Characters which are not allowed in XML 1.0 at all can't be escaped and are dropped.
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => {}
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use surrealdb::sql::Id;
+    use crate::definitions::IntelliThing;
+    use super::*;
+
+    fn channel(updated: Option<DateTime<Utc>>) -> FeedChannel {
+        FeedChannel {
+            title: "Tom & Jerry's <blog>".to_string(),
+            description: "Latest posts".to_string(),
+            site_url: "https://example.com".to_string(),
+            self_url: "https://example.com/feed.atom".to_string(),
+            updated,
+        }
+    }
+
+    fn post() -> FeedPost {
+        FeedPost {
+            id: IntelliThing { id: Id::from("abc") },
+            title: "Fish & \"Chips\" <3".to_string(),
+            summary: Some("It's 1 < 2 & 3 > 2".to_string()),
+            author_names: vec!["O'Brien".to_string()],
+            tags: vec!["a&b".to_string()],
+            posted: Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap(),
+            updated: Utc.with_ymd_and_hms(2024, 2, 1, 8, 30, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(escape_xml("<a href=\"x\">Tom & Jerry's</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;");
+    }
+
+    #[test]
+    fn drops_characters_xml_cant_contain() {
+        assert_eq!(escape_xml("a\u{0}b\u{8}c\td\ne"), "abc\td\ne");
+    }
+
+    #[test]
+    fn rss_escapes_posts() {
+        let xml = render_rss(&channel(Some(post().updated)), &[post()]);
+
+        assert!(xml.contains("<title>Tom &amp; Jerry&apos;s &lt;blog&gt;</title>"));
+        assert!(xml.contains("<title>Fish &amp; &quot;Chips&quot; &lt;3</title>"));
+        assert!(xml.contains("<description>It&apos;s 1 &lt; 2 &amp; 3 &gt; 2</description>"));
+        assert!(xml.contains("<dc:creator>O&apos;Brien</dc:creator>"));
+        assert!(xml.contains("<category>a&amp;b</category>"));
+        assert!(!xml.contains("Fish & "));
+    }
+
+    #[test]
+    fn atom_escapes_posts() {
+        let xml = render_atom(&channel(Some(post().updated)), &[post()]);
+
+        assert!(xml.contains("<title>Fish &amp; &quot;Chips&quot; &lt;3</title>"));
+        assert!(xml.contains("<summary>It&apos;s 1 &lt; 2 &amp; 3 &gt; 2</summary>"));
+        assert!(xml.contains("<author><name>O&apos;Brien</name></author>"));
+        assert!(xml.contains("<category term=\"a&amp;b\"/>"));
+    }
+
+    #[test]
+    fn atom_entries_are_updated_by_their_last_edit() {
+        let xml = render_atom(&channel(Some(post().updated)), &[post()]);
+
+        assert!(xml.contains("<published>2024-01-01T12:00:00Z</published>"));
+        assert!(xml.contains("<updated>2024-02-01T08:30:00Z</updated>"));
+    }
+
+    #[test]
+    fn empty_feed_has_no_build_date() {
+        let rss = render_rss(&channel(None), &[]);
+        assert!(!rss.contains("<lastBuildDate>"));
+
+        // Atom can't leave it out, it isn't the unix epoch either
+        let atom = render_atom(&channel(None), &[]);
+        assert!(atom.contains("<updated>"));
+        assert!(!atom.contains("1970"));
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{delete, get, HttpResponse, post, Scope, web};
+use actix_web::web::Json;
+use utoipa::OpenApi;
+use uuid::Uuid;
+use crate::audit::{Auditor, snapshot};
+use crate::definitions::{BodyInvite, Invite, User};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+#[derive(OpenApi)]
+#[openapi(paths(invites_get, invite_post, invite_delete))]
+pub(crate) struct InviteApi;
+
+pub fn invite_service() -> Scope {
+    web::scope("/api/v1/invites")
+        .service(invites_get)
+        .service(invite_post)
+        .service(invite_delete)
+}
+
+#[utoipa::path(tag = "invites", responses((status = 200, body = Vec<Invite>)), security(("bearer" = [])))]
+#[get("")]
+async fn invites_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let invites = database_manager.fetch_invites().await?;
+    Ok(HttpResponse::Ok().json(invites))
+}
+
+#[utoipa::path(tag = "invites", request_body = BodyInvite, responses((status = 201, body = Invite)), security(("bearer" = [])))]
+#[post("")]
+async fn invite_post(
+    user: User,
+    body: Json<BodyInvite>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let invite = body.into_inner();
+
+    if invite.max_uses.is_some_and(|max_uses| max_uses < 1) {
+        return Err(ApiError::BadRequest("An invite has to be usable at least once".to_string()));
+    }
+
+    let code = Uuid::new_v4().simple().to_string();
+    let created_invite = database_manager.add_invite(code, invite, &user).await?
+        .ok_or(ApiError::Internal("the invite wasn't created".to_string()))?;
+    auditor.record(&user, "invite.create", format!("invite:{}", created_invite.id), None, snapshot(&created_invite)).await;
+
+    Ok(HttpResponse::Created().json(created_invite))
+}
+
+#[utoipa::path(tag = "invites", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/{code}")]
+async fn invite_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let code = path.into_inner();
+
+    user.require_admin()?;
+
+    let deleted_invite = database_manager.delete_invite(code).await?
+        .ok_or(ApiError::NotFound)?;
+    auditor.record(&user, "invite.delete", format!("invite:{}", deleted_invite.id), snapshot(&deleted_invite), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
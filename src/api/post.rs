@@ -1,106 +1,505 @@
 use std::path::PathBuf;
-use actix_web::{Error, get, HttpResponse, patch, put, Scope, web, post, delete};
+use actix_web::{get, HttpResponse, patch, put, Scope, web, post, delete};
 use actix_web::web::Json;
 use futures_util::StreamExt;
-use log::error;
-use crate::definitions::{BodyPost, BodyUser, Post, User};
+use serde::Deserialize;
+use serde_json::json;
+use similar::{ChangeTag, TextDiff};
+use utoipa::{IntoParams, OpenApi};
+use crate::audit::{Auditor, snapshot};
+use crate::config::LimitConfig;
+use crate::definitions::{BodyPost, DiffLine, Post, PostRevision, User};
+use crate::error::ApiError;
 use crate::storage::database_manager::DatabaseManager;
 use crate::storage::storage_manager::StorageManager;
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DiffQuery {
+    from: String,
+    to: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(posts_get, posts_trash_get, post_get, post_delete, post_trash_restore, post_post, post_patch,
+    post_revisions_get, post_revisions_diff_get, post_revision_get, post_revision_restore, post_image_put, post_image_get))]
+pub(crate) struct PostApi;
+
 pub fn blog_service() -> Scope {
     web::scope("/api/v1/posts")
         .service(posts_get)
+        // Has to be registered before post_get, otherwise "trash" is taken as a post id
+        .service(posts_trash_get)
         .service(post_get)
         .service(post_delete)
+        .service(post_trash_restore)
         .service(post_post)
+        .service(post_patch)
+        .service(post_revisions_get)
+        // Has to be registered before post_revision_get, otherwise "diff" is taken as a revision id
+        .service(post_revisions_diff_get)
+        .service(post_revision_get)
+        .service(post_revision_restore)
+        .service(post_image_put)
+        .service(post_image_get)
 }
 
+#[utoipa::path(tag = "posts", responses((status = 200, body = Vec<Post>)), security(("bearer" = [])))]
 #[get("")]
 async fn posts_get(
     user: User,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_admin()?;
 
-    let posts = match database_manager.fetch_posts().await {
-        Ok(posts) => posts,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    };
+    let posts = database_manager.fetch_posts().await?;
     Ok(HttpResponse::Ok().json(posts))
 }
 
+// Unpublished posts are only visible to the people who can edit them
+#[utoipa::path(tag = "posts", responses((status = 200, body = Post)), security((), ("bearer" = [])))]
 #[get("/{postId}")]
 async fn post_get(
+    user: Option<User>,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
     let post_id = path.into_inner();
 
-    let found_post: Option<Post> = match database_manager.fetch_post(post_id).await {
-        Ok(found_post) => {
-            found_post
-        }
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    };
+    let found_post = fetch_visible_post(user.as_ref(), post_id, &database_manager).await?;
 
-    match found_post {
-        Some(found_user) => {
-            Ok(HttpResponse::Ok().json(found_user))
-        }
-        None => {
-            Ok(HttpResponse::NotFound().finish())
-        }
-    }
+    Ok(HttpResponse::Ok().json(found_post))
 }
 
+#[utoipa::path(tag = "posts", responses((status = 200)), security(("bearer" = [])))]
 #[delete("/{postId}")]
 async fn post_delete(
     user: User,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
     let post_id = path.into_inner();
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_admin()?;
 
-    match database_manager.delete_post(post_id).await {
-        Ok(_) => {
-            Ok(HttpResponse::Ok().finish())
-        },
-        Err(_) => {
-            Ok(HttpResponse::InternalServerError().finish())
-        }
-    }
+    // Only moves the post to the trash, it is purged for good later
+    let deleted_post = database_manager.delete_post(post_id).await?
+        .ok_or(ApiError::PostNotFound)?;
+    auditor.record(&user, "post.delete", format!("post:{}", deleted_post.id), snapshot(&deleted_post), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(tag = "posts", responses((status = 200, body = Vec<Post>)), security(("bearer" = [])))]
+#[get("/trash")]
+async fn posts_trash_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let posts = database_manager.fetch_deleted_posts().await?;
+    Ok(HttpResponse::Ok().json(posts))
 }
 
+#[utoipa::path(tag = "posts", responses((status = 200, body = Post)), security(("bearer" = [])))]
+#[post("/{postId}/restore")]
+async fn post_trash_restore(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let post_id = path.into_inner();
+
+    user.require_admin()?;
+
+    let restored_post = database_manager.restore_post(post_id).await?
+        .ok_or(ApiError::PostNotFound)?;
+    auditor.record(&user, "post.undelete", format!("post:{}", restored_post.id), None, snapshot(&restored_post)).await;
+
+    Ok(HttpResponse::Ok().json(restored_post))
+}
+
+#[utoipa::path(tag = "posts", request_body = BodyPost, responses((status = 200, body = Post)), security(("bearer" = [])))]
 #[post("")]
 async fn post_post(
     user: User,
     body: Json<BodyPost>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let mut post = body.into_inner();
+
+    if post.title.is_none() {
+        return Err(ApiError::BadRequest("A post needs a title".to_string()));
+    }
+
+    if let Some(authors) = post.authors.take() {
+        post.authors = Some(check_authors(&user, authors, &database_manager).await?);
+    }
+
+    let created_post = database_manager.add_post(post, &user).await?;
+    if let Some(created_post) = &created_post {
+        auditor.record(&user, "post.create", format!("post:{}", created_post.id), None, snapshot(created_post)).await;
+    }
+
+    Ok(HttpResponse::Ok().json(created_post))
+}
+
+#[utoipa::path(tag = "posts", request_body = BodyPost, responses((status = 200, body = Post)), security(("bearer" = [])))]
+#[patch("/{postId}")]
+async fn post_patch(
+    user: User,
+    body: Json<BodyPost>,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner();
+
+    let mut modified_post = fetch_editable_post(&user, post_id, &database_manager).await?;
+    let before = snapshot(&modified_post);
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
+    let body = body.into_inner();
+
+    let authors = match body.authors {
+        Some(authors) => Some(check_authors(&user, authors, &database_manager).await?),
+        None => None,
+    };
+
+    if let Some(title) = body.title {
+        modified_post.title = title;
+    }
+    if let Some(summary) = body.summary {
+        modified_post.summary = Some(summary);
+    }
+    if let Some(content) = body.content {
+        modified_post.content = Some(content);
     }
+    if let Some(tags) = body.tags {
+        modified_post.tags = tags;
+    }
+    if let Some(project) = body.project {
+        modified_post.project = Some(project);
+    }
+
+    let updated_post = database_manager.update_post(&modified_post, authors, &user, None).await?;
+    auditor.record(&user, "post.update", format!("post:{}", modified_post.id), before, updated_post.as_ref().and_then(snapshot)).await;
+
+    Ok(HttpResponse::Ok().json(updated_post))
+}
+
+#[utoipa::path(tag = "posts", responses((status = 200, body = Vec<PostRevision>)), security(("bearer" = [])))]
+#[get("/{postId}/revisions")]
+async fn post_revisions_get(
+    user: User,
+    path: web::Path<String>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner();
+
+    let post = fetch_editable_post(&user, post_id, &database_manager).await?;
+
+    let revisions = database_manager.fetch_revisions(post.id.to_string()).await?;
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+#[utoipa::path(tag = "posts", params(DiffQuery), responses((status = 200, body = Vec<DiffLine>)), security(("bearer" = [])))]
+#[get("/{postId}/revisions/diff")]
+async fn post_revisions_diff_get(
+    user: User,
+    path: web::Path<String>,
+    query: web::Query<DiffQuery>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner();
+    let query = query.into_inner();
+
+    let post = fetch_editable_post(&user, post_id, &database_manager).await?;
+
+    let from = database_manager.fetch_revision(post.id.to_string(), query.from).await?
+        .ok_or(ApiError::RevisionNotFound)?;
+
+    let to = database_manager.fetch_revision(post.id.to_string(), query.to).await?
+        .ok_or(ApiError::RevisionNotFound)?;
+
+    Ok(HttpResponse::Ok().json(diff_revisions(&from, &to)))
+}
+
+#[utoipa::path(tag = "posts", responses((status = 200, body = PostRevision)), security(("bearer" = [])))]
+#[get("/{postId}/revisions/{revisionId}")]
+async fn post_revision_get(
+    user: User,
+    path: web::Path<(String, String)>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let (post_id, revision_id) = path.into_inner();
 
-    let post = body.into_inner();
+    let post = fetch_editable_post(&user, post_id, &database_manager).await?;
 
-    match database_manager.add_post(post).await {
-        Ok(_) => {
+    let revision = database_manager.fetch_revision(post.id.to_string(), revision_id).await?
+        .ok_or(ApiError::RevisionNotFound)?;
+
+    Ok(HttpResponse::Ok().json(revision))
+}
+
+// Makes the content of an older revision the current content of the post.
+// This doesn't rewrite history, the restore itself shows up as the newest revision.
+#[utoipa::path(tag = "posts", responses((status = 200, body = Post)), security(("bearer" = [])))]
+#[post("/{postId}/revisions/{revisionId}/restore")]
+async fn post_revision_restore(
+    user: User,
+    path: web::Path<(String, String)>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let (post_id, revision_id) = path.into_inner();
+
+    let mut restored_post = fetch_editable_post(&user, post_id, &database_manager).await?;
+    let before = snapshot(&restored_post);
+
+    let revision = database_manager.fetch_revision(restored_post.id.to_string(), revision_id.clone()).await?
+        .ok_or(ApiError::RevisionNotFound)?;
+
+    restore_revision(&mut restored_post, revision);
+
+    let updated_post = database_manager.update_post(&restored_post, None, &user, Some(revision_id)).await?;
+    auditor.record(&user, "post.restore", format!("post:{}", restored_post.id), before, updated_post.as_ref().and_then(snapshot)).await;
+
+    Ok(HttpResponse::Ok().json(updated_post))
+}
+
+// Removes duplicates and makes sure every author exists. Everyone but admins has to stay an author,
+// otherwise co-authors could take a post away from each other.
+async fn check_authors(user: &User, authors: Vec<String>, database_manager: &DatabaseManager) -> Result<Vec<String>, ApiError> {
+    let mut unique_authors: Vec<String> = Vec::new();
+    for author in authors {
+        if !unique_authors.contains(&author) {
+            unique_authors.push(author);
         }
-        Err(err) => {
-            error!("Could not post post {err}");
-            return Ok(HttpResponse::InternalServerError().finish())
+    }
+
+    if unique_authors.is_empty() {
+        return Err(ApiError::BadRequest("A post needs at least one author".to_string()));
+    }
+
+    if !user.admin && !unique_authors.contains(&user.id.to_string()) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let existing = database_manager.fetch_existing_user_ids(unique_authors.clone()).await?;
+    let unknown: Vec<&str> = unique_authors.iter()
+        .filter(|author| !existing.contains(author))
+        .map(|author| author.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(ApiError::BadRequest(format!("Unknown authors: {}", unknown.join(", "))));
+    }
+
+    Ok(unique_authors)
+}
+
+// Answers unpublished posts with 404 unless the user could edit them, so their existence doesn't leak
+async fn fetch_visible_post(user: Option<&User>, post_id: String, database_manager: &DatabaseManager) -> Result<Post, ApiError> {
+    let post = database_manager.fetch_post(post_id).await?
+        .ok_or(ApiError::PostNotFound)?;
+
+    let can_edit = user.is_some_and(|user| post.require_editor(user).is_ok());
+    if !post.is_published() && !can_edit {
+        return Err(ApiError::PostNotFound);
+    }
+
+    Ok(post)
+}
+
+async fn fetch_editable_post(user: &User, post_id: String, database_manager: &DatabaseManager) -> Result<Post, ApiError> {
+    let post = database_manager.fetch_post(post_id).await?
+        .ok_or(ApiError::PostNotFound)?;
+
+    post.require_editor(user)?;
+    Ok(post)
+}
+
+// Only the content is taken from the revision, likes, views and the publishing date stay as they are
+fn restore_revision(post: &mut Post, revision: PostRevision) {
+    post.title = revision.title;
+    post.summary = revision.summary;
+    post.content = revision.content;
+    post.tags = revision.tags;
+    post.project = revision.project;
+}
+
+// Line diff of the whole revision, the title and summary are part of it so changes to them show up too
+fn diff_revisions(from: &PostRevision, to: &PostRevision) -> Vec<DiffLine> {
+    let old_text = revision_text(from);
+    let new_text = revision_text(to);
+
+    TextDiff::from_lines(&old_text, &new_text)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            change: match change.tag() {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Insert => "insert",
+            }.to_string(),
+            old_line: change.old_index().map(|index| index + 1),
+            new_line: change.new_index().map(|index| index + 1),
+            value: change.value().trim_end_matches('\n').to_string(),
+        })
+        .collect()
+}
+
+fn revision_text(revision: &PostRevision) -> String {
+    format!(
+        "# {}\n{}\n\n{}\n",
+        revision.title,
+        revision.summary.as_deref().unwrap_or_default(),
+        revision.content.as_deref().unwrap_or_default()
+    )
+}
+
+#[utoipa::path(tag = "posts", request_body(content(("image/png"))), responses((status = 200)), security(("bearer" = [])))]
+#[put("/{postId}/image")]
+async fn post_image_put(
+    user: User,
+    mut payload: web::Payload,
+    path: web::Path<String>,
+    auditor: Auditor,
+    limits: web::Data<LimitConfig>,
+    storage_manager: web::Data<StorageManager>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner();
+
+    let post = fetch_editable_post(&user, post_id, &database_manager).await?;
+
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = payload.next().await {
+        bytes.extend_from_slice(&chunk?);
+
+        if bytes.len() > limits.max_upload_bytes() {
+            return Err(ApiError::PayloadTooLarge);
         }
     }
 
+    storage_manager.put(&PathBuf::from(format!("postimages/{}", post.id)), &bytes).await?;
+    auditor.record(&user, "post.image", format!("post:{}", post.id), None, Some(json!({ "size": bytes.len() }))).await;
+
     Ok(HttpResponse::Ok().finish())
-}
\ No newline at end of file
+}
+
+// The image of an unpublished post is as hidden as the post itself
+#[utoipa::path(tag = "posts", responses((status = 200, content(("image/png")))), security((), ("bearer" = [])))]
+#[get("/{postId}/image")]
+async fn post_image_get(
+    user: Option<User>,
+    path: web::Path<String>,
+    storage_manager: web::Data<StorageManager>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let post_id = path.into_inner();
+
+    let post = fetch_visible_post(user.as_ref(), post_id, &database_manager).await?;
+
+    let data = storage_manager.get(&PathBuf::from(format!("postimages/{}", post.id))).await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .body(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use surrealdb::sql::Id;
+    use crate::definitions::IntelliThing;
+    use super::*;
+
+    fn thing(id: &str) -> IntelliThing {
+        IntelliThing { id: Id::from(id) }
+    }
+
+    fn revision(title: &str, summary: Option<&str>, content: &str) -> PostRevision {
+        PostRevision {
+            id: thing("revision"),
+            post: thing("post"),
+            editor: thing("editor"),
+            created: Utc::now().into(),
+            title: title.to_string(),
+            summary: summary.map(str::to_string),
+            content: Some(content.to_string()),
+            tags: vec!["rust".to_string()],
+            project: Some("homepage".to_string()),
+            restored_from: None,
+        }
+    }
+
+    fn changes(diff: &[DiffLine]) -> Vec<(&str, Option<usize>, Option<usize>, &str)> {
+        diff.iter()
+            .map(|line| (line.change.as_str(), line.old_line, line.new_line, line.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn revision_text_contains_title_summary_and_content() {
+        assert_eq!(revision_text(&revision("Title", Some("Summary"), "Body")), "# Title\nSummary\n\nBody\n");
+        assert_eq!(revision_text(&revision("Title", None, "Body")), "# Title\n\n\nBody\n");
+    }
+
+    #[test]
+    fn identical_revisions_only_have_equal_lines() {
+        let diff = diff_revisions(&revision("Title", None, "a\nb"), &revision("Title", None, "a\nb"));
+        assert!(diff.iter().all(|line| line.change == "equal"));
+    }
+
+    #[test]
+    fn changed_line_is_deleted_and_inserted() {
+        let diff = diff_revisions(&revision("Title", None, "a\nb\nc"), &revision("Title", None, "a\nB\nc"));
+
+        assert_eq!(changes(&diff), vec![
+            ("equal", Some(1), Some(1), "# Title"),
+            ("equal", Some(2), Some(2), ""),
+            ("equal", Some(3), Some(3), ""),
+            ("equal", Some(4), Some(4), "a"),
+            ("delete", Some(5), None, "b"),
+            ("insert", None, Some(5), "B"),
+            ("equal", Some(6), Some(6), "c"),
+        ]);
+    }
+
+    #[test]
+    fn title_changes_show_up_in_the_diff() {
+        let diff = diff_revisions(&revision("Old", None, "a"), &revision("New", None, "a"));
+
+        assert!(changes(&diff).contains(&("delete", Some(1), None, "# Old")));
+        assert!(changes(&diff).contains(&("insert", None, Some(1), "# New")));
+    }
+
+    #[test]
+    fn restore_takes_the_content_of_the_revision() {
+        let mut post = Post {
+            id: thing("post"),
+            authors: vec![thing("author")],
+            likes: 3,
+            views: 10,
+            title: "Current".to_string(),
+            summary: None,
+            content: Some("current".to_string()),
+            tags: Vec::new(),
+            project: None,
+            posted: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            deleted_at: None,
+        };
+
+        restore_revision(&mut post, revision("Old", Some("Old summary"), "old"));
+
+        assert_eq!(post.title, "Old");
+        assert_eq!(post.summary.as_deref(), Some("Old summary"));
+        assert_eq!(post.content.as_deref(), Some("old"));
+        assert_eq!(post.tags, vec!["rust".to_string()]);
+        assert_eq!(post.project.as_deref(), Some("homepage"));
+        // Everything else is left alone
+        assert_eq!((post.likes, post.views), (3, 10));
+        assert_eq!(post.authors, vec![thing("author")]);
+        assert_eq!(post.posted, Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use actix_web::{get, HttpResponse, web};
+use chrono::{DateTime, SecondsFormat, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tracing::error;
+use serde::Deserialize;
+use utoipa::IntoParams;
+use crate::api::feed::escape_xml;
+use crate::definitions::{PageMeta, SiteInfo};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+use crate::storage::storage_manager::StorageManager;
+
+// The sitemap protocol doesn't allow more urls than this in a single file
+const SITEMAP_LIMIT: usize = 50_000;
+// Everything but the unreserved characters of RFC 3986 is encoded in a path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MetaQuery {
+    path: String,
+}
+
+pub fn seo_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(sitemap_get)
+        .service(meta_get);
+}
+
+#[utoipa::path(tag = "seo", responses((status = 200, body = String, content_type = "application/xml")))]
+#[get("/sitemap.xml")]
+async fn sitemap_get(
+    database_manager: web::Data<DatabaseManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+
+    let posts = database_manager.fetch_published_posts(None, None, SITEMAP_LIMIT as i64).await?;
+    let projects = database_manager.fetch_projects().await?;
+
+    // The pages which change the least go first, if the site outgrows the limit the oldest posts are left out
+    // Edits count as well, the newest post isn't necessarily the last one which changed
+    let mut urls = vec![(format!("{}/", site_info.url), posts.iter().map(|post| post.updated).max())];
+    for project in &projects {
+        urls.push((format!("{}/projects/{}", site_info.url, encode_segment(&project.project)), Some(project.updated)));
+    }
+    for post in &posts {
+        urls.push((format!("{}/posts/{}", site_info.url, encode_segment(&post.id.to_string())), Some(post.updated)));
+    }
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+
+    for (location, last_modified) in urls.into_iter().take(SITEMAP_LIMIT) {
+        write_sitemap_url(&mut xml, &location, last_modified);
+    }
+
+    xml.push_str("</urlset>\n");
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml; charset=utf-8")
+        .body(xml))
+}
+
+fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+fn write_sitemap_url(xml: &mut String, location: &str, last_modified: Option<DateTime<Utc>>) {
+    xml.push_str("<url>");
+    let _ = write!(xml, "<loc>{}</loc>", escape_xml(location));
+    if let Some(last_modified) = last_modified {
+        let _ = write!(xml, "<lastmod>{}</lastmod>", last_modified.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    xml.push_str("</url>\n");
+}
+
+/// Returns the metadata for a page of the frontend
+///
+/// Unknown paths fall back to the metadata of the site itself, since most of them are
+/// still valid pages of the frontend.
+#[utoipa::path(tag = "seo", params(MetaQuery), responses((status = 200, body = PageMeta)))]
+#[get("/api/v1/meta")]
+async fn meta_get(
+    query: web::Query<MetaQuery>,
+    database_manager: web::Data<DatabaseManager>,
+    storage_manager: web::Data<StorageManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+
+    let path = query.into_inner().path;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let url = format!("{}/{}", site_info.url, path.trim_start_matches('/'));
+
+    let meta = match segments.as_slice() {
+        ["posts", post_id] => {
+            let post = database_manager.fetch_published_post(post_id.to_string()).await?
+                .ok_or(ApiError::PostNotFound)?;
+
+            let image_path = PathBuf::from(format!("postimages/{}", post.id));
+            let image = match storage_manager.size(&image_path).await {
+                Ok(Some(_)) => Some(format!("{}/api/v1/posts/{}/image", site_info.url, post.id)),
+                Ok(None) => site_info.image.clone(),
+                Err(err) => {
+                    error!("Couldn't look up post image {}", err);
+                    site_info.image.clone()
+                }
+            };
+
+            let mut meta = page_meta(&site_info, post.title, post.summary.unwrap_or_else(|| site_info.description.clone()), url, image, "article");
+            meta.open_graph.insert("article:published_time".to_string(), post.posted.to_rfc3339_opts(SecondsFormat::Secs, true));
+            if !post.author_names.is_empty() {
+                meta.open_graph.insert("article:author".to_string(), post.author_names.join(", "));
+            }
+            for tag in post.tags {
+                // BTreeMap can't hold duplicate keys, so only the first tag ends up in the card
+                meta.open_graph.entry("article:tag".to_string()).or_insert(tag);
+            }
+            meta
+        }
+        // The sitemap encodes the project, the path can come from it
+        ["projects", project] => {
+            let project = percent_decode_str(project).decode_utf8_lossy();
+            let title = format!("{} - {}", project, site_info.title);
+            let description = format!("Posts about {} from {}", project, site_info.title);
+            page_meta(&site_info, title, description, url, site_info.image.clone(), "website")
+        }
+        _ => page_meta(&site_info, site_info.title.clone(), site_info.description.clone(), url, site_info.image.clone(), "website"),
+    };
+
+    Ok(HttpResponse::Ok().json(meta))
+}
+
+fn page_meta(site_info: &SiteInfo, title: String, description: String, url: String, image: Option<String>, og_type: &str) -> PageMeta {
+    let mut open_graph = BTreeMap::new();
+    open_graph.insert("og:type".to_string(), og_type.to_string());
+    open_graph.insert("og:site_name".to_string(), site_info.title.clone());
+    open_graph.insert("og:title".to_string(), title.clone());
+    open_graph.insert("og:description".to_string(), description.clone());
+    open_graph.insert("og:url".to_string(), url.clone());
+
+    let mut twitter = BTreeMap::new();
+    twitter.insert("twitter:title".to_string(), title.clone());
+    twitter.insert("twitter:description".to_string(), description.clone());
+
+    match &image {
+        Some(image) => {
+            open_graph.insert("og:image".to_string(), image.clone());
+            twitter.insert("twitter:card".to_string(), "summary_large_image".to_string());
+            twitter.insert("twitter:image".to_string(), image.clone());
+        }
+        None => {
+            twitter.insert("twitter:card".to_string(), "summary".to_string());
+        }
+    }
+
+    PageMeta {
+        title,
+        description,
+        url,
+        image,
+        open_graph,
+        twitter,
+    }
+}
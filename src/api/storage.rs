@@ -0,0 +1,51 @@
+use actix_web::{delete, get, HttpResponse, Scope, web};
+use serde_json::json;
+use utoipa::OpenApi;
+use crate::audit::Auditor;
+use crate::definitions::User;
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+use crate::storage::reconcile::{find_orphans, remove_orphans};
+use crate::storage::storage_manager::StorageManager;
+
+#[derive(OpenApi)]
+#[openapi(paths(orphans_get, orphans_delete))]
+pub(crate) struct StorageApi;
+
+pub fn storage_service() -> Scope {
+    web::scope("/api/v1/storage")
+        .service(orphans_get)
+        .service(orphans_delete)
+}
+
+// Files in the storage which don't belong to any user or post
+#[utoipa::path(tag = "storage", responses((status = 200, body = Vec<String>, description = "Files no record refers to")), security(("bearer" = [])))]
+#[get("/orphans")]
+async fn orphans_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>,
+    storage_manager: web::Data<StorageManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let orphans = find_orphans(&database_manager, &storage_manager).await?;
+    Ok(HttpResponse::Ok().json(orphans))
+}
+
+#[utoipa::path(tag = "storage", responses((status = 200, body = Vec<String>, description = "The removed files")), security(("bearer" = [])))]
+#[delete("/orphans")]
+async fn orphans_delete(
+    user: User,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>,
+    storage_manager: web::Data<StorageManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let removed = remove_orphans(&database_manager, &storage_manager).await?;
+    if !removed.is_empty() {
+        auditor.record(&user, "storage.gc", "storage".to_string(), Some(json!({ "files": removed })), None).await;
+    }
+
+    Ok(HttpResponse::Ok().json(removed))
+}
@@ -1,268 +1,438 @@
 use std::path::PathBuf;
-use actix_web::{Error, get, HttpResponse, patch, put, Scope, web, post, delete};
+use actix_web::{get, HttpResponse, patch, put, Scope, web, post, delete};
 use actix_web::web::Json;
 use futures_util::StreamExt;
-use log::error;
-use crate::definitions::{BodyUser, User};
+use tracing::error;
+use serde_json::json;
+use utoipa::OpenApi;
+use crate::audit::{Auditor, snapshot};
+use crate::config::LimitConfig;
+use crate::definitions::{BodyUser, Post, PublicProfile, SiteInfo, User};
+use crate::error::ApiError;
+use crate::passwords::hash_password;
 use crate::storage::database_manager::DatabaseManager;
 use crate::storage::storage_manager::StorageManager;
 
 pub fn user_service() -> Scope {
     web::scope("/api/v1/users")
         .service(users_get)
+        // Has to be registered before user_get, otherwise "trash" is taken as a user id
+        .service(users_trash_get)
         .service(user_get)
         .service(user_delete)
+        .service(user_restore)
+        .service(user_two_factor_delete)
+        .service(user_sessions_delete)
         .service(user_post)
         .service(user_exists)
         .service(user_patch)
         .service(user_picture_put)
         .service(user_picture_get)
+        .service(user_posts_get)
+        .service(user_profile_get)
 }
 
+#[utoipa::path(tag = "users", responses((status = 200, body = Vec<User>)), security(("bearer" = [])))]
 #[get("")]
 async fn users_get(
     user: User,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_admin()?;
 
-    let users = match database_manager.fetch_users().await {
-        Ok(users) => users,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    };
+    let users = database_manager.fetch_users().await?;
     Ok(HttpResponse::Ok().json(users))
 }
 
+#[utoipa::path(tag = "users", responses((status = 200, body = User)), security(("bearer" = [])))]
 #[get("/{userId}")]
 async fn user_get(
     user: User,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
     let user_id = path.into_inner();
 
-    if !user.admin && !user.compare(&user_id) {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_self_or_admin(&user_id)?;
 
-    let found_user: Option<User> = match database_manager.fetch_user(user_id).await {
-        Ok(found_user) => {
-            found_user
-        },
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    };
+    let found_user = database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
 
-    match found_user {
-        Some(found_user) => {
-            Ok(HttpResponse::Ok().json(found_user))
-        }
-        None => {
-            Ok(HttpResponse::NotFound().finish())
-        }
-    }
+    Ok(HttpResponse::Ok().json(found_user))
 }
 
+#[utoipa::path(tag = "users", responses((status = 200)), security(("bearer" = [])))]
 #[delete("/{userId}")]
 async fn user_delete(
     user: User,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
     let user_id = path.into_inner();
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_admin()?;
 
-    match database_manager.delete_user(user_id).await {
-        Ok(_) => {
-            Ok(HttpResponse::Ok().finish())
-        },
-        Err(_) => {
-            Ok(HttpResponse::InternalServerError().finish())
-        }
-    }
+    // Only moves the user to the trash, see DatabaseManager::delete_user for what happens to their posts
+    let deleted_user = database_manager.delete_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+    auditor.record(&user, "user.delete", format!("user:{}", deleted_user.id), snapshot(&deleted_user), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(tag = "users", responses((status = 200, body = Vec<User>)), security(("bearer" = [])))]
+#[get("/trash")]
+async fn users_trash_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let users = database_manager.fetch_deleted_users().await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[utoipa::path(tag = "users", responses((status = 200, body = User)), security(("bearer" = [])))]
+#[post("/{userId}/restore")]
+async fn user_restore(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let user_id = path.into_inner();
+
+    user.require_admin()?;
+
+    let restored_user = database_manager.restore_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+    auditor.record(&user, "user.undelete", format!("user:{}", restored_user.id), None, snapshot(&restored_user)).await;
+
+    Ok(HttpResponse::Ok().json(restored_user))
 }
 
+// For users who lost their authenticator and their recovery codes
+#[utoipa::path(tag = "users", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/{userId}/2fa")]
+async fn user_two_factor_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let user_id = path.into_inner();
+
+    user.require_admin()?;
+
+    let found_user = database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    database_manager.disable_totp(found_user.id.to_string()).await?;
+    auditor.record(&user, "user.two_factor.disable", format!("user:{}", found_user.id), None, None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Logs the user out everywhere, e.g. when their account was compromised.
+// Personal access tokens are revoked as well, whoever took over the account could have created some.
+#[utoipa::path(tag = "users", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/{userId}/sessions")]
+async fn user_sessions_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let user_id = path.into_inner();
+
+    user.require_self_or_admin(&user_id)?;
+
+    let found_user = database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let sessions = database_manager.delete_sessions(found_user.id.to_string()).await?;
+    auditor.record(&user, "user.sessions.revoke", format!("user:{}", found_user.id), snapshot(&sessions), None).await;
+
+    let tokens = database_manager.delete_api_tokens(found_user.id.to_string()).await?;
+    auditor.record(&user, "user.tokens.revoke", format!("user:{}", found_user.id), snapshot(&tokens), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(tag = "users", request_body = BodyUser, responses((status = 200)), security(("bearer" = [])))]
 #[post("")]
 async fn user_post(
     user: User,
     body: Json<BodyUser>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let mut new_user = body.into_inner(); // Extract the user from Json
+
+    let (Some(name), Some(email)) = (new_user.name.clone(), new_user.email.clone()) else {
+        return Err(ApiError::BadRequest("A user needs a name and an email".to_string()));
+    };
 
-    if !user.admin {
-        return Ok(HttpResponse::Unauthorized().finish())
+    if database_manager.is_user_taken(name, email, None).await? {
+        return Err(ApiError::Conflict("The name or email is already taken".to_string()));
     }
 
-    let user = body.into_inner(); // Extract the user from Json
+    if let Some(password) = new_user.password.take() {
+        new_user.password = Some(hash_password(password).await?);
+    }
 
-    match database_manager.add_user(user).await {
-        Ok(_) => {
-        }
-        Err(err) => {
-            error!("Could not update user {err}");
-            return Ok(HttpResponse::InternalServerError().finish())
-        }
+    let created_users = database_manager.add_user(new_user).await?;
+    for created_user in &created_users {
+        auditor.record(&user, "user.create", format!("user:{}", created_user.id), None, snapshot(created_user)).await;
     }
 
     Ok(HttpResponse::Ok().finish())
 }
 
 
+#[utoipa::path(tag = "users", responses((status = 200)), security(("bearer" = [])))]
 #[get("/exists/{userId}")]
 async fn user_exists(
     _user: User,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
 
     let user_id = path.into_inner();
 
-    match database_manager.fetch_user(user_id).await {
-        Ok(found_user) => {
-            match found_user {
-                Some(_) => {
-                    Ok(HttpResponse::Ok().finish())
-                }
-                None => {
-                    Ok(HttpResponse::NotFound().finish())
-                }
-            }
-        },
-        Err(_) => {
-            Ok(HttpResponse::InternalServerError().finish())
-        }
-    }
+    database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
 
+    Ok(HttpResponse::Ok().finish())
 }
 
 
+#[utoipa::path(tag = "users", request_body = BodyUser, responses((status = 200, body = BodyUser)), security(("bearer" = [])))]
 #[patch("/{userId}")]
 async fn user_patch(
     user: User,
     body: Json<BodyUser>,
     path: web::Path<String>,
-    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
     let user_id = path.into_inner();
 
-    if !user.admin && !user.compare(&user_id) {
-        return Ok(HttpResponse::Unauthorized().finish())
+    user.require_self_or_admin(&user_id)?;
+
+    // Otherwise everyone could make themselves an admin
+    if body.admin.is_some() || body.email_verified.is_some() {
+        user.require_admin()?;
     }
 
-    let found_user: Option<User> = match database_manager.fetch_user(user_id).await {
-        Ok(found_user) => found_user,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    };
+    // Personal access tokens have no session. With the email a leaked one could reset the password,
+    // so changing how the user logs in needs a real login.
+    if user.session.is_none() && (body.email.is_some() || body.password.is_some()) {
+        return Err(ApiError::PermissionDenied);
+    }
 
-    match found_user {
-        None => {
-            Ok(HttpResponse::NotFound().finish())
-        }
-        Some(mut modified_user) => {
-            // Apply partial updates based on the fields provided in the request body
-            // Can we make this a bit more clean? Especially if we have more fields in the future, this is ass to maintain
-            if let Some(name) = body.name.clone() {
-                modified_user.name = name;
-            }
-            if let Some(admin) = body.admin.clone() {
-                modified_user.admin = admin;
-            }
-            if let Some(email) = body.email.clone() {
-                modified_user.email = email;
-            }
-            if let Some(password) = body.password.clone() {
-                modified_user.password = password;
-            }
-            if let Some(firstname) = body.firstname.clone() {
-                modified_user.firstname = Option::from(firstname);
-            }
-            if let Some(lastname) = body.lastname.clone() {
-                modified_user.lastname = Option::from(lastname);
-            }
+    let mut modified_user = database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+    let before = snapshot(&modified_user);
 
-            match database_manager.update_user(&modified_user).await {
-                Ok(_) => {
-                    // We use the body here since I do not want to send the password back in the response
-                    //if it was not included in the request.
-                    Ok(HttpResponse::Ok().json(body))
-                }
-                Err(err) => {
-                    error!("Couldn't patch user {}", err);
-                    Ok(HttpResponse::InternalServerError().finish())
-                }
-            }
+    // Apply partial updates based on the fields provided in the request body
+    // Can we make this a bit more clean? Especially if we have more fields in the future, this is ass to maintain
+    if let Some(name) = body.name.clone() {
+        modified_user.name = name;
+    }
+    if let Some(admin) = body.admin {
+        modified_user.admin = admin;
+    }
+    if let Some(email) = body.email.clone() {
+        // A new address has to be verified again
+        if email != modified_user.email {
+            modified_user.email_verified = false;
         }
+        modified_user.email = email;
+    }
+    if let Some(email_verified) = body.email_verified {
+        modified_user.email_verified = email_verified;
+    }
+    if let Some(password) = body.password.clone() {
+        modified_user.password = hash_password(password).await?;
     }
+    if let Some(firstname) = body.firstname.clone() {
+        modified_user.firstname = Option::from(firstname);
+    }
+    if let Some(lastname) = body.lastname.clone() {
+        modified_user.lastname = Option::from(lastname);
+    }
+    if let Some(display_name) = body.display_name.clone() {
+        modified_user.display_name = Option::from(display_name);
+    }
+    if let Some(bio) = body.bio.clone() {
+        modified_user.bio = Option::from(bio);
+    }
+    if let Some(links) = body.links.clone() {
+        modified_user.links = links;
+    }
+    if let Some(visibility) = body.visibility.clone() {
+        modified_user.visibility = visibility;
+    }
+
+    if (body.name.is_some() || body.email.is_some())
+        && database_manager.is_user_taken(modified_user.name.clone(), modified_user.email.clone(), Some(modified_user.id.to_string())).await? {
+        return Err(ApiError::Conflict("The name or email is already taken".to_string()));
+    }
+
+    database_manager.update_user(&modified_user).await?;
+
+    // Whoever knew the old password shouldn't stay logged in
+    if body.password.is_some() {
+        database_manager.delete_sessions(modified_user.id.to_string()).await?;
+    }
+
+    auditor.record(&user, "user.update", format!("user:{}", modified_user.id), before, snapshot(&modified_user)).await;
+
+    // We use the body here since I do not want to send the password back in the response
+    //if it was not included in the request.
+    Ok(HttpResponse::Ok().json(body))
 }
 
+#[utoipa::path(tag = "users", request_body(content(("image/png"))), responses((status = 200)), security(("bearer" = [])))]
 #[put("/{userId}/image")]
 async fn user_picture_put(
     user: User,
     mut payload: web::Payload,
     path: web::Path<String>,
-    storage_manager: web::Data<StorageManager>) -> Result<HttpResponse, Error> {
+    auditor: Auditor,
+    limits: web::Data<LimitConfig>,
+    storage_manager: web::Data<StorageManager>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
     let user_id = path.into_inner();
 
     let mut bytes = Vec::new();
 
-    if !user.admin && !user.compare(&user_id) {
-        return Ok(HttpResponse::Unauthorized().finish())
-    }
+    user.require_self_or_admin(&user_id)?;
+
+    // The path can also be the name or email, the file is named after the id so the orphan scan knows its owner
+    let found_user = database_manager.fetch_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
 
     while let Some(chunk) = payload.next().await {
-        let chunk = chunk.expect("Error reading chunk");
-        bytes.extend_from_slice(&chunk);
-    }
+        bytes.extend_from_slice(&chunk?);
 
-    match storage_manager.put(&PathBuf::from(format!("userimages/{}", user_id)), &bytes).await {
-        Ok(_) => {
-            Ok(HttpResponse::Ok().finish())
-        }
-        Err(err) => {
-            error!("Couldn't save picture {}", err);
-            Ok(HttpResponse::InternalServerError().finish())
+        if bytes.len() > limits.max_upload_bytes() {
+            return Err(ApiError::PayloadTooLarge);
         }
     }
+
+    storage_manager.put(&PathBuf::from(format!("userimages/{}", found_user.id)), &bytes).await?;
+    auditor.record(&user, "user.image", format!("user:{}", found_user.id), None, Some(json!({ "size": bytes.len() }))).await;
+
+    Ok(HttpResponse::Ok().finish())
 }
 
+// Public, unless the user hid their avatar, then only they and admins can see it
+#[utoipa::path(tag = "users", responses((status = 200, content(("image/png")))), security((), ("bearer" = [])))]
 #[get("/{userId}/image")]
 async fn user_picture_get(
-    mut payload: web::Payload,
+    user: Option<User>,
     path: web::Path<String>,
-    storage_manager: web::Data<StorageManager>) -> Result<HttpResponse, Error> {
+    storage_manager: web::Data<StorageManager>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
     let user_id = path.into_inner();
 
-    let mut bytes = Vec::new();
+    let found_user = database_manager.fetch_public_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
 
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk.expect("Error reading chunk");
-        bytes.extend_from_slice(&chunk);
+    let is_self_or_admin = user.is_some_and(|user| user.admin || user.id == found_user.id);
+    if !found_user.visibility.avatar && !is_self_or_admin {
+        return Err(ApiError::NotFound);
+    }
+
+    let full_path = PathBuf::from(format!("userimages/{}", found_user.id));
+    let data = storage_manager.get(&full_path).await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .body(data))
+}
+
+// Public, but unpublished posts are only listed for admins and the user themselves.
+// Like the image, the list is hidden from everyone else if the user hid their posts on the profile.
+#[utoipa::path(tag = "users", responses((status = 200, body = Vec<Post>)), security((), ("bearer" = [])))]
+#[get("/{userId}/posts")]
+async fn user_posts_get(
+    user: Option<User>,
+    path: web::Path<String>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+
+    let found_user = database_manager.fetch_public_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let include_unpublished = user.is_some_and(|user| user.admin || user.id == found_user.id);
+    if !found_user.visibility.posts && !include_unpublished {
+        return Err(ApiError::NotFound);
     }
 
-    let full_path = PathBuf::from(format!("userimages/{}", user_id));
-    match storage_manager.get(&full_path).await {
-        Ok(file_data) => {
-            match file_data {
-                Some(data) => {
-                    Ok(HttpResponse::Ok()
-                        .content_type("image/png")
-                        .body(data))
-                }
-                None => {
-                    Ok(HttpResponse::NotFound().finish())
-                }
+    let posts = database_manager.fetch_user_posts(found_user.id.to_string(), include_unpublished).await?;
+    Ok(HttpResponse::Ok().json(posts))
+}
+
+// Public view of a user, the user decides which fields show up here.
+// Like the other public routes it only finds users by id or name, the email would tell who has an account.
+#[utoipa::path(tag = "users", responses((status = 200, body = PublicProfile)))]
+#[get("/{userId}/profile")]
+async fn user_profile_get(
+    path: web::Path<String>,
+    database_manager: web::Data<DatabaseManager>,
+    storage_manager: web::Data<StorageManager>,
+    site_info: web::Data<SiteInfo>) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+
+    let found_user = database_manager.fetch_public_user(user_id).await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let visibility = &found_user.visibility;
+
+    let posts = if visibility.posts {
+        database_manager.fetch_user_posts(found_user.id.to_string(), false).await?
+    } else {
+        Vec::new()
+    };
+
+    let mut projects: Vec<String> = posts.iter().filter_map(|post| post.project.clone()).collect();
+    projects.sort();
+    projects.dedup();
+
+    let avatar = if visibility.avatar {
+        match storage_manager.size(&PathBuf::from(format!("userimages/{}", found_user.id))).await {
+            Ok(Some(_)) => Some(format!("{}/api/v1/users/{}/image", site_info.url, found_user.id)),
+            Ok(None) => None,
+            Err(err) => {
+                // A missing avatar shouldn't take the whole profile down
+                error!("Couldn't look up user picture {}", err);
+                None
             }
         }
-        Err(err) => {
-            error!("Couldn't serve picture {}", err);
-            Ok(HttpResponse::InternalServerError().finish())
-        }
-    }
-}
\ No newline at end of file
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(PublicProfile {
+        id: found_user.id.to_string(),
+        name: found_user.name,
+        display_name: found_user.display_name.filter(|_| visibility.display_name),
+        firstname: found_user.firstname.filter(|_| visibility.firstname),
+        lastname: found_user.lastname.filter(|_| visibility.lastname),
+        bio: found_user.bio.filter(|_| visibility.bio),
+        avatar,
+        links: if visibility.links { found_user.links } else { Default::default() },
+        posts,
+        projects,
+    }))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(users_get, users_trash_get, user_get, user_delete, user_restore, user_two_factor_delete, user_sessions_delete,
+    user_post, user_exists, user_patch, user_picture_put, user_picture_get, user_posts_get, user_profile_get))]
+pub(crate) struct UserApi;
@@ -0,0 +1,85 @@
+use std::future::{ready, Ready};
+use actix_web::{FromRequest, get, HttpRequest, HttpResponse, Scope, web};
+use actix_web::dev::Payload;
+use actix_web::web::Data;
+use chrono::{DateTime, Utc};
+use tracing::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, OpenApi};
+use crate::definitions::{AuditEntry, User};
+use crate::error::ApiError;
+use crate::middleware::client_ip::client_ip;
+use crate::storage::database_manager::{DatabaseManager, PaginationParams};
+
+/// Writes entries to the audit log
+///
+/// Extracted by every handler which changes data, so the entries know where the request came from.
+pub(crate) struct Auditor {
+    database_manager: DatabaseManager,
+    ip: Option<String>,
+}
+
+impl Auditor {
+    /// Appends an entry to the audit log
+    ///
+    /// Failing to write the entry doesn't fail the request, the action already happened at this point.
+    pub(crate) async fn record(&self, actor: &User, action: &str, target: String, before: Option<Value>, after: Option<Value>) {
+        if let Err(err) = self.database_manager.add_audit_entry(actor, action, &target, before, after, self.ip.clone()).await {
+            error!("Couldn't write {} on {} by {} to the audit log: {}", action, target, actor.id, err);
+        }
+    }
+}
+
+impl FromRequest for Auditor {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let database_manager = req.app_data::<Data<DatabaseManager>>().unwrap().get_ref().clone();
+
+        ready(Ok(Auditor {
+            database_manager,
+            ip: client_ip(req).map(|ip| ip.to_string()),
+        }))
+    }
+}
+
+/// Turns a record into the snapshot stored in the audit log, uses the same fields the api would return
+pub(crate) fn snapshot(value: &impl Serialize) -> Option<Value> {
+    serde_json::to_value(value).ok()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuditFilter {
+    actor: Option<String>,
+    action: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(audit_get))]
+pub(crate) struct AuditApi;
+
+pub fn audit_service() -> Scope {
+    web::scope("/api/v1/audit")
+        .service(audit_get)
+}
+
+#[utoipa::path(tag = "audit", params(AuditFilter, PaginationParams), responses((status = 200, body = Vec<AuditEntry>)), security(("bearer" = [])))]
+#[get("")]
+async fn audit_get(
+    user: User,
+    filter: web::Query<AuditFilter>,
+    pagination: web::Query<PaginationParams>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let filter = filter.into_inner();
+    let entries = database_manager.fetch_audit_entries(filter.actor, filter.action, filter.since, filter.until, &pagination).await?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
@@ -1,14 +1,32 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::time::SystemTime;
-use actix_web::{Error, error, FromRequest, get, HttpRequest, HttpResponse, post, Scope, web};
+use actix_web::{FromRequest, get, HttpRequest, HttpResponse, HttpResponseBuilder, post, Scope, web};
 use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
 use actix_web::web::{Data, Json};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use jsonwebtoken::{encode, decode, Header as JwtHeader, Algorithm, Validation, EncodingKey, DecodingKey, errors::Result as JwtResult};
+use chrono::Duration;
+use jsonwebtoken::{encode, decode, decode_header, Header as JwtHeader, Validation, errors::ErrorKind as JwtErrorKind, errors::Result as JwtResult};
+use tracing::error;
 use serde::{Deserialize, Serialize};
-use crate::definitions::{User};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use utoipa::{OpenApi, ToSchema};
+use crate::audit::{Auditor, snapshot};
+use crate::config::TokenConfig;
+use crate::cookie_auth::{check_csrf, token_from_cookie, CookieAuth};
+use crate::definitions::{Registration, RegistrationMode, SiteInfo, User};
+use crate::error::ApiError;
+use crate::keys::JwtKeys;
+use crate::lockout::LoginLimiter;
+use crate::mail::mailer::{Mail, Mailer};
+use crate::middleware::client_ip::client_ip;
+use crate::passwords::{hash_password, verify_password};
 use crate::storage::database_manager::DatabaseManager;
+use crate::sessions;
+use crate::totp;
+use crate::tokens;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
@@ -16,35 +34,139 @@ struct Claims {
     iat: usize,          // Optional. Issued at (as UTC timestamp)
     iss: String,         // Optional. Issuer
     sub: String,         // Optional. Subject (whom token refers to)
+    sid: String,         // The session of the login, the token stops working once it is ended
+}
+
+// Claims of the tokens sent by mail and of login challenges.
+// They carry an audience, which keeps them from being accepted as login tokens.
+#[derive(Debug, Serialize, Deserialize)]
+struct ActionClaims {
+    exp: usize,
+    iat: usize,
+    iss: String,
+    sub: String,
+    aud: String,
+    // The address the token was sent to, only set for email verification
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+    // The password was correct, the second factor is still missing
+    LoginChallenge,
+}
+
+impl TokenPurpose {
+    fn audience(&self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify_email",
+            TokenPurpose::ResetPassword => "reset_password",
+            TokenPurpose::LoginChallenge => "login_challenge",
+        }
+    }
+
+    fn lifetime(&self, lifetimes: &TokenConfig) -> Duration {
+        match self {
+            TokenPurpose::VerifyEmail => Duration::hours(lifetimes.verify_email_hours),
+            TokenPurpose::ResetPassword => Duration::minutes(lifetimes.reset_password_minutes),
+            TokenPurpose::LoginChallenge => Duration::minutes(lifetimes.login_challenge_minutes),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct AuthManager {
-    algorithm: Algorithm,
-    encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
+    keys: JwtKeys,
+    // Set if logins are kept in cookies instead of being handed to the frontend
+    cookie_auth: Option<CookieAuth>,
+    lifetimes: TokenConfig,
 }
 
 impl AuthManager {
-    pub(crate) fn new(algorithm: Algorithm, encoding_key: EncodingKey, decoding_key: DecodingKey) -> Self {
+    pub(crate) fn new(keys: JwtKeys, cookie_auth: Option<CookieAuth>, lifetimes: TokenConfig) -> Self {
         AuthManager {
-            algorithm,
-            encoding_key,
-            decoding_key,
+            keys,
+            cookie_auth,
+            lifetimes,
         }
     }
 
+    pub(crate) fn login_lifetime(&self) -> Duration {
+        Duration::days(self.lifetimes.login_days)
+    }
+
+    fn action_token_lifetime(&self, purpose: TokenPurpose) -> Duration {
+        purpose.lifetime(&self.lifetimes)
+    }
+
+    pub(crate) fn cookie_auth(&self) -> Option<&CookieAuth> {
+        self.cookie_auth.as_ref()
+    }
+
     fn create_token(&self, claims: &Claims) -> JwtResult<String> {
-        encode(&JwtHeader::new(self.algorithm), claims, &self.encoding_key)
+        self.create_signed(claims)
     }
 
     fn validate_token(&self, token: String) -> JwtResult<Claims> {
-        Ok(decode::<Claims>(&token, &self.decoding_key, &Validation::new(self.algorithm))?.claims)
+        self.decode(&token, Validation::new(self.keys.algorithm()))
     }
+
+    fn create_action_token(&self, user: &User, purpose: TokenPurpose) -> JwtResult<String> {
+        let iat = now();
+        let claims = ActionClaims {
+            exp: iat + purpose.lifetime(&self.lifetimes).num_seconds() as usize,
+            iat,
+            iss: "intelligence".to_string(),
+            sub: user.id.to_string(),
+            aud: purpose.audience().to_string(),
+            email: match purpose {
+                TokenPurpose::VerifyEmail => Some(user.email.clone()),
+                TokenPurpose::ResetPassword | TokenPurpose::LoginChallenge => None,
+            },
+        };
+
+        self.create_signed(&claims)
+    }
+
+    fn validate_action_token(&self, token: &str, purpose: TokenPurpose) -> JwtResult<ActionClaims> {
+        self.validate_signed(token, purpose.audience())
+    }
+
+    /// Signs arbitrary claims, they need an `exp` and an `aud` so they can't be mistaken for a login token
+    pub(crate) fn create_signed<T: Serialize>(&self, claims: &T) -> JwtResult<String> {
+        let mut header = JwtHeader::new(self.keys.algorithm());
+        header.kid = self.keys.kid();
+
+        encode(&header, claims, self.keys.encoding_key())
+    }
+
+    /// Checks the signature, expiry and audience of claims created by [`AuthManager::create_signed`]
+    pub(crate) fn validate_signed<T: DeserializeOwned>(&self, token: &str, audience: &str) -> JwtResult<T> {
+        let mut validation = Validation::new(self.keys.algorithm());
+        validation.set_audience(&[audience]);
+
+        self.decode(token, validation)
+    }
+
+    // Verifies the token with the key named in its header, tokens signed with an unknown or retired key are rejected
+    fn decode<T: DeserializeOwned>(&self, token: &str, validation: Validation) -> JwtResult<T> {
+        let header = decode_header(token)?;
+        let decoding_key = self.keys.decoding_key(header.kid)
+            .ok_or(JwtErrorKind::InvalidSignature)?;
+
+        Ok(decode::<T>(token, decoding_key, &validation)?.claims)
+    }
+}
+
+pub(crate) fn now() -> usize {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as usize
 }
 
 impl FromRequest for User {
-    type Error = Error;
+    type Error = ApiError;
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
@@ -54,85 +176,518 @@ impl FromRequest for User {
         let database_manager = req.app_data::<Data<DatabaseManager>>().unwrap().get_ref().clone();
 
         Box::pin(async move {
-            let bearer_auth = BearerAuth::from_request(&req, &mut Payload::None).await;
-            match bearer_auth {
-                Ok(auth) => {
-                    let token = auth.token();
-
-                    match auth_manager.validate_token(token.to_string()) {
-                        Ok(claims) => {
-                            match database_manager.fetch_user(claims.sub).await {
-                                Ok(user) => {
-                                    match user {
-                                        Some(user) => Ok(user),
-                                        None => Err(error::ErrorUnauthorized("User not found"))
-                                    }
-                                },
-                                Err(_) => Err(error::ErrorUnauthorized("Failed to fetch user from database"))
-                            }
-                        },
-                        Err(_) => Err(error::ErrorUnauthorized("Invalid token"))
-                    }
-                },
-                Err(_) => Err(error::ErrorUnauthorized("Invalid token"))
-            }
+            // A bearer token wins over the cookie, only the cookie needs to be protected against forged requests
+            let token = if req.headers().contains_key(AUTHORIZATION) {
+                let auth = BearerAuth::from_request(&req, &mut Payload::None).await
+                    .map_err(|_| ApiError::InvalidToken)?;
+
+                if tokens::is_api_token(auth.token()) {
+                    return tokens::authenticate(&database_manager, auth.token(), req.method(), req.path()).await;
+                }
+
+                auth.token().to_string()
+            } else if let Some(token) = auth_manager.cookie_auth().and_then(|_| token_from_cookie(&req)) {
+                check_csrf(&req)?;
+                token
+            } else {
+                return Err(ApiError::MissingToken);
+            };
+
+            let claims = auth_manager.validate_token(token)?;
+            sessions::check_session(&database_manager, &claims.sid, &claims.sub).await?;
+
+            // The token is still valid but the user got deleted in the meantime
+            let mut user = database_manager.fetch_user(claims.sub).await?
+                .ok_or(ApiError::InvalidToken)?;
+            user.session = Some(claims.sid);
+
+            Ok(user)
         })
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct LoginCredentials {
     username: String,
     password: String,
 }
 
-#[derive(Serialize)]
-struct LoginToken {
-    token: String
+// What a successful login answers with, a challenge if the user still needs to provide their second factor
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum LoginResponse {
+    Token { token: String },
+    Challenge { challenge: String },
+    // The token went into a cookie, the frontend has to send the csrf token along with its requests
+    Cookie { csrf_token: String },
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SecondFactor {
+    challenge: String,
+    code: String,
 }
 
+#[derive(Deserialize, ToSchema)]
+struct TwoFactorCode {
+    code: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TwoFactorEnrolment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ForgotPassword {
+    email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ResetPassword {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct VerifyEmail {
+    token: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(auth_me, auth_login, auth_login_second_factor, auth_two_factor_enrol, auth_two_factor_confirm, auth_two_factor_disable,
+    auth_register, auth_forgot, auth_reset, auth_verify_send, auth_verify, auth_logout))]
+pub(crate) struct AuthApi;
+
 pub fn auth_service() -> Scope {
     web::scope("/api/v1/auth")
         .service(auth_me)
         .service(auth_login)
+        .service(auth_login_second_factor)
+        .service(auth_two_factor_enrol)
+        .service(auth_two_factor_confirm)
+        .service(auth_two_factor_disable)
+        .service(auth_register)
+        .service(auth_forgot)
+        .service(auth_reset)
+        .service(auth_verify_send)
+        .service(auth_verify)
+        .service(auth_logout)
 }
 
+#[utoipa::path(tag = "auth", request_body = LoginCredentials, responses((status = 200, body = LoginResponse)))]
 #[post("/login")]
 async fn auth_login(
+    req: HttpRequest,
     login_credentials: Json<LoginCredentials>,
     database_manager: Data<DatabaseManager>,
     auth_manager: Data<AuthManager>,
-) -> Result<HttpResponse, Error> {
-    let user = database_manager.fetch_user(login_credentials.username.clone()).await;
-    match user {
-        Ok(user) => {
-            match user {
-                Some(user) => {
-                    if user.validate_password(login_credentials.password.clone()) {
-                        let iat = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as usize;
-                        let claims = Claims {
-                            exp: iat + (30 * 24 * 60 * 60),
-                            iat,
-                            iss: "intelligence".to_string(),
-                            sub: user.id.to_string(),
-                        };
-
-                        match auth_manager.create_token(&claims) {
-                            Ok(token) => Ok(HttpResponse::Ok().json(LoginToken { token })),
-                            Err(_) => Err(error::ErrorInternalServerError("Failed to create token"))
-                        }
-                    } else {
-                        Err(error::ErrorUnauthorized("Invalid credentials"))
-                    }
-                },
-                None => Err(error::ErrorUnauthorized("Invalid credentials"))
+    login_limiter: Data<LoginLimiter>,
+) -> Result<HttpResponse, ApiError> {
+    let address = client_ip(&req);
+    login_limiter.check_address(address)?;
+
+    let user = match database_manager.fetch_user(login_credentials.username.clone()).await? {
+        Some(user) => user,
+        None => {
+            login_limiter.record_failure(address, None).await;
+            return Err(ApiError::InvalidCredentials);
+        }
+    };
+
+    // Checked before the password, otherwise a locked account could still be used to guess it
+    login_limiter.check_account(&user).await?;
+
+    if !verify_password(user.password.clone(), login_credentials.password.clone()).await? {
+        login_limiter.record_failure(address, Some(&user)).await;
+        return Err(ApiError::InvalidCredentials);
+    }
+
+    // With two-factor authentication the failures are only forgotten once the code was right as well
+    if !user.two_factor {
+        login_limiter.record_success(&user).await?;
+    }
+
+    let response = complete_login(&auth_manager, &database_manager, &req, &user).await?;
+
+    let mut builder = HttpResponse::Ok();
+    let body = issue_login(&auth_manager, response, &mut builder);
+    Ok(builder.json(body))
+}
+
+#[utoipa::path(tag = "auth", request_body = SecondFactor, responses((status = 200, body = LoginResponse)))]
+#[post("/login/2fa")]
+async fn auth_login_second_factor(
+    req: HttpRequest,
+    body: Json<SecondFactor>,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+    login_limiter: Data<LoginLimiter>,
+) -> Result<HttpResponse, ApiError> {
+    let address = client_ip(&req);
+    login_limiter.check_address(address)?;
+
+    let claims = auth_manager.validate_action_token(&body.challenge, TokenPurpose::LoginChallenge)?;
+
+    let user = database_manager.fetch_user(claims.sub).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    // Six digits are guessed a lot faster than a password
+    login_limiter.check_account(&user).await?;
+
+    if !totp::verify_second_factor(&database_manager, &user, &body.code).await? {
+        login_limiter.record_failure(address, Some(&user)).await;
+        return Err(ApiError::InvalidCode);
+    }
+
+    login_limiter.record_success(&user).await?;
+
+    let token = create_login_token(&auth_manager, &database_manager, &req, &user).await?;
+
+    let mut builder = HttpResponse::Ok();
+    let body = issue_login(&auth_manager, LoginResponse::Token { token }, &mut builder);
+    Ok(builder.json(body))
+}
+
+/// Logs in a user whose identity was just proven, by their password or an external provider
+///
+/// The token is only handed out once the second factor was checked by auth_login_second_factor.
+pub(crate) async fn complete_login(auth_manager: &AuthManager, database_manager: &DatabaseManager, req: &HttpRequest, user: &User) -> Result<LoginResponse, ApiError> {
+    if user.two_factor {
+        let challenge = auth_manager.create_action_token(user, TokenPurpose::LoginChallenge)
+            .map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))?;
+
+        return Ok(LoginResponse::Challenge { challenge });
+    }
+
+    Ok(LoginResponse::Token { token: create_login_token(auth_manager, database_manager, req, user).await? })
+}
+
+/// In cookie mode moves the login token into cookies set on `builder`, returns what is left for the body
+pub(crate) fn issue_login(auth_manager: &AuthManager, response: LoginResponse, builder: &mut HttpResponseBuilder) -> LoginResponse {
+    match (response, auth_manager.cookie_auth()) {
+        (LoginResponse::Token { token }, Some(cookie_auth)) => {
+            let (csrf_token, cookies) = cookie_auth.login_cookies(token);
+            for cookie in cookies {
+                builder.cookie(cookie);
             }
-        },
-        Err(_) => Err(error::ErrorInternalServerError("Failed to fetch user from database"))
+
+            LoginResponse::Cookie { csrf_token }
+        }
+        (response, _) => response,
     }
 }
 
+// Ends the session the request was made with
+#[utoipa::path(tag = "auth", responses((status = 200)), security(("bearer" = [])))]
+#[post("/logout")]
+async fn auth_logout(
+    user: User,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(session) = &user.session {
+        database_manager.delete_session(user.id.to_string(), session.clone()).await?;
+        auditor.record(&user, "session.logout", format!("session:{}", session), None, None).await;
+    }
+
+    let mut builder = HttpResponse::Ok();
+    if let Some(cookie_auth) = auth_manager.cookie_auth() {
+        for cookie in cookie_auth.logout_cookies() {
+            builder.cookie(cookie);
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+// Every login token belongs to a session, which expires together with it
+async fn create_login_token(auth_manager: &AuthManager, database_manager: &DatabaseManager, req: &HttpRequest, user: &User) -> Result<String, ApiError> {
+    let session = sessions::start_session(database_manager, req, user, auth_manager.login_lifetime()).await?;
+
+    let claims = Claims {
+        exp: session.expires.timestamp() as usize,
+        iat: now(),
+        iss: "intelligence".to_string(),
+        sub: user.id.to_string(),
+        sid: session.id.to_string(),
+    };
+
+    auth_manager.create_token(&claims)
+        .map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))
+}
+
+// Starts the enrolment, two-factor authentication is only turned on once a code was confirmed
+#[utoipa::path(tag = "auth", responses((status = 200, body = TwoFactorEnrolment)), security(("bearer" = [])))]
+#[post("/2fa/enrol")]
+async fn auth_two_factor_enrol(
+    user: User,
+    database_manager: Data<DatabaseManager>,
+    site_info: Data<SiteInfo>,
+) -> Result<HttpResponse, ApiError> {
+    if user.two_factor {
+        return Err(ApiError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+
+    let secret = totp::generate_secret();
+    let uri = totp::otpauth_uri(&secret, &site_info.title, &user.name)?;
+
+    database_manager.set_totp_pending(user.id.to_string(), secret.clone()).await?;
+
+    Ok(HttpResponse::Ok().json(TwoFactorEnrolment { secret, uri }))
+}
+
+#[utoipa::path(tag = "auth", request_body = TwoFactorCode, responses((status = 200, body = RecoveryCodes)), security(("bearer" = [])))]
+#[post("/2fa/confirm")]
+async fn auth_two_factor_confirm(
+    user: User,
+    body: Json<TwoFactorCode>,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+) -> Result<HttpResponse, ApiError> {
+    let secret = user.totp_pending.clone()
+        .ok_or(ApiError::BadRequest("There is no enrolment to confirm".to_string()))?;
+
+    if totp::verify_code(&secret, body.code.trim())?.is_none() {
+        return Err(ApiError::InvalidCode);
+    }
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+    database_manager.enable_totp(user.id.to_string(), secret, hashes).await?;
+    auditor.record(&user, "user.two_factor.enable", format!("user:{}", user.id), None, None).await;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+#[utoipa::path(tag = "auth", request_body = TwoFactorCode, responses((status = 200)), security(("bearer" = [])))]
+#[post("/2fa/disable")]
+async fn auth_two_factor_disable(
+    user: User,
+    body: Json<TwoFactorCode>,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+) -> Result<HttpResponse, ApiError> {
+    if !user.two_factor {
+        return Err(ApiError::BadRequest("Two-factor authentication is not enabled".to_string()));
+    }
+
+    if !totp::verify_second_factor(&database_manager, &user, &body.code).await? {
+        return Err(ApiError::InvalidCode);
+    }
+
+    database_manager.disable_totp(user.id.to_string()).await?;
+    auditor.record(&user, "user.two_factor.disable", format!("user:{}", user.id), None, None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Depending on the registration mode this needs an invite, which also decides if the new account is an admin
+#[utoipa::path(tag = "auth", request_body = Registration, responses((status = 201, body = User)))]
+#[post("/register")]
+async fn auth_register(
+    body: Json<Registration>,
+    auditor: Auditor,
+    registration_mode: Data<RegistrationMode>,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+    mailer: Data<Mailer>,
+    site_info: Data<SiteInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let registration = body.into_inner();
+
+    if *registration_mode.get_ref() == RegistrationMode::Closed {
+        return Err(ApiError::RegistrationClosed);
+    }
+    if *registration_mode.get_ref() == RegistrationMode::InviteOnly && registration.invite.is_none() {
+        return Err(ApiError::InvalidInvite);
+    }
+
+    // Names can't look like an email, both are used to log in
+    if registration.name.trim().is_empty() || registration.name.contains('@') {
+        return Err(ApiError::BadRequest("The name can't be empty or contain an @".to_string()));
+    }
+    if !registration.email.contains('@') {
+        return Err(ApiError::BadRequest("The email address is invalid".to_string()));
+    }
+    if registration.password.is_empty() {
+        return Err(ApiError::BadRequest("The password can't be empty".to_string()));
+    }
+
+    if database_manager.is_user_taken(registration.name.clone(), registration.email.clone(), None).await? {
+        return Err(ApiError::Conflict("The name or email is already taken".to_string()));
+    }
+
+    // The invite is only counted if the user is created as well, so a typo or a taken name doesn't use it up
+    let password = hash_password(registration.password).await?;
+    let user = database_manager.register_user(registration.name, registration.email, false, password, registration.invite).await?
+        .ok_or(ApiError::InvalidInvite)?;
+    auditor.record(&user, "user.register", format!("user:{}", user.id), None, snapshot(&user)).await;
+
+    let token = auth_manager.create_action_token(&user, TokenPurpose::VerifyEmail)
+        .map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))?;
+    send_mail_in_background(mailer.get_ref().clone(), verification_mail(&user, &token, auth_manager.action_token_lifetime(TokenPurpose::VerifyEmail), &site_info));
+
+    Ok(HttpResponse::Created().json(user))
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200, body = User)), security(("bearer" = [])))]
 #[get("/me")]
-async fn auth_me(user: User) -> Result<HttpResponse, Error> {
+async fn auth_me(user: User) -> Result<HttpResponse, ApiError> {
     Ok(HttpResponse::Ok().json(user))
-}
\ No newline at end of file
+}
+// Always answers the same way, so it can't be used to find out which addresses have an account
+#[utoipa::path(tag = "auth", request_body = ForgotPassword, responses((status = 202)))]
+#[post("/forgot")]
+async fn auth_forgot(
+    body: Json<ForgotPassword>,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+    mailer: Data<Mailer>,
+    site_info: Data<SiteInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let user = database_manager.fetch_user(body.email.clone()).await?
+        .filter(|user| user.email == body.email);
+
+    if let Some(user) = user {
+        let token = auth_manager.create_action_token(&user, TokenPurpose::ResetPassword)
+            .map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))?;
+
+        send_mail_in_background(mailer.get_ref().clone(), Mail {
+            to: user.email,
+            subject: format!("Reset your password for {}", site_info.title),
+            body: format!(
+                "Someone asked to reset the password of your account {}.\n\n\
+                Open this link within the next {} to choose a new one:\n{}/reset-password?token={}\n\n\
+                If that wasn't you, you can ignore this mail.",
+                user.name, format_lifetime(auth_manager.action_token_lifetime(TokenPurpose::ResetPassword)), site_info.url, token
+            ),
+        });
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[utoipa::path(tag = "auth", request_body = ResetPassword, responses((status = 200)))]
+#[post("/reset")]
+async fn auth_reset(
+    body: Json<ResetPassword>,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+) -> Result<HttpResponse, ApiError> {
+    if body.password.is_empty() {
+        return Err(ApiError::BadRequest("The password can't be empty".to_string()));
+    }
+
+    let claims = auth_manager.validate_action_token(&body.token, TokenPurpose::ResetPassword)?;
+
+    let mut user = database_manager.fetch_user(claims.sub).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    // Every token issued before the last password change is used up, which makes them single use
+    if user.password_changed.is_some_and(|changed| claims.iat as i64 <= changed.timestamp()) {
+        return Err(ApiError::InvalidToken);
+    }
+
+    user.password = hash_password(body.password.clone()).await?;
+    database_manager.update_user(&user).await?;
+    // The database only notices changed passwords, resetting to the same one has to use up the token as well
+    database_manager.mark_password_changed(user.id.to_string()).await?;
+
+    // Whoever knew the old password shouldn't stay logged in
+    database_manager.delete_sessions(user.id.to_string()).await?;
+    auditor.record(&user, "user.password.reset", format!("user:{}", user.id), None, None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(tag = "auth", responses((status = 202)), security(("bearer" = [])))]
+#[post("/verify/send")]
+async fn auth_verify_send(
+    user: User,
+    auth_manager: Data<AuthManager>,
+    mailer: Data<Mailer>,
+    site_info: Data<SiteInfo>,
+) -> Result<HttpResponse, ApiError> {
+    if user.email_verified {
+        return Err(ApiError::BadRequest("The email address is already verified".to_string()));
+    }
+
+    let token = auth_manager.create_action_token(&user, TokenPurpose::VerifyEmail)
+        .map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))?;
+
+    mailer.send(&verification_mail(&user, &token, auth_manager.action_token_lifetime(TokenPurpose::VerifyEmail), &site_info)).await.map_err(|err| ApiError::Internal(format!("failed to send mail: {err}")))?;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[utoipa::path(tag = "auth", request_body = VerifyEmail, responses((status = 200)))]
+#[post("/verify")]
+async fn auth_verify(
+    body: Json<VerifyEmail>,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+    auth_manager: Data<AuthManager>,
+) -> Result<HttpResponse, ApiError> {
+    let claims = auth_manager.validate_action_token(&body.token, TokenPurpose::VerifyEmail)?;
+
+    let mut user = database_manager.fetch_user(claims.sub).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    // The address changed since the token was sent
+    if claims.email.as_ref() != Some(&user.email) {
+        return Err(ApiError::InvalidToken);
+    }
+
+    if !user.email_verified {
+        user.email_verified = true;
+        database_manager.update_user(&user).await?;
+        auditor.record(&user, "user.email.verify", format!("user:{}", user.id), None, Some(json!({ "email": user.email }))).await;
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+fn verification_mail(user: &User, token: &str, valid_for: Duration, site_info: &SiteInfo) -> Mail {
+    Mail {
+        to: user.email.clone(),
+        subject: format!("Verify your email address for {}", site_info.title),
+        body: format!(
+            "Open this link within the next {} to verify the email address of your account {}:\n{}/verify-email?token={}",
+            format_lifetime(valid_for), user.name, site_info.url, token
+        ),
+    }
+}
+
+// The lifetimes are configured in whole days, hours or minutes, so the largest unit which fits is exact
+fn format_lifetime(lifetime: Duration) -> String {
+    let (amount, unit) = if lifetime.num_days() > 0 && lifetime == Duration::days(lifetime.num_days()) {
+        (lifetime.num_days(), "day")
+    } else if lifetime.num_hours() > 0 && lifetime == Duration::hours(lifetime.num_hours()) {
+        (lifetime.num_hours(), "hour")
+    } else {
+        (lifetime.num_minutes(), "minute")
+    };
+
+    match amount {
+        1 => unit.to_string(),
+        _ => format!("{amount} {unit}s"),
+    }
+}
+
+fn send_mail_in_background(mailer: Mailer, mail: Mail) {
+    actix_web::rt::spawn(async move {
+        if let Err(err) = mailer.send(&mail).await {
+            error!("Couldn't send mail to {}: {}", mail.to, err);
+        }
+    });
+}
@@ -0,0 +1,747 @@
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Deserializer};
+use actix_web::http::header::HeaderValue;
+use crate::definitions::RegistrationMode;
+use crate::middleware::client_ip::parse_network;
+use crate::oauth::OAuthAccountPolicy;
+
+// Upper bound of the lifetimes and retention times, long enough for any sensible setting and far from where
+// chrono's durations and dates overflow
+const MAX_DAYS: i64 = 10 * 365;
+// Longer locks don't protect more, the account is effectively gone for its owner either way
+const MAX_LOCK_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+/// Every setting of the server
+///
+/// The settings are read from a TOML file, `config.toml` in the working directory or the file `CONFIG_FILE` points to,
+/// see `config.example.toml` for all of them. Environment variables override the file, they keep the names the
+/// settings had before there was a file (e.g. `DB_HOST`), see [`Config::apply_env`]. Settings which are neither in
+/// the file nor in the environment get their default.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) tracing: TracingConfig,
+    pub(crate) site: SiteConfig,
+    pub(crate) cors: CorsConfig,
+    pub(crate) headers: HeadersConfig,
+    pub(crate) database: DatabaseConfig,
+    pub(crate) storage: StorageConfig,
+    pub(crate) auth: AuthConfig,
+    pub(crate) tokens: TokenConfig,
+    pub(crate) limits: LimitConfig,
+    pub(crate) lockout: LockoutConfig,
+    pub(crate) mail: MailConfig,
+    pub(crate) oauth: OAuthSettings,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub(crate) bind: String,
+    pub(crate) workers: usize,
+    // A tracing filter, e.g. "info" or "info,intelligence_homepage=debug"
+    pub(crate) log_level: String,
+    pub(crate) log_format: LogFormat,
+    // The public url of this api, e.g. the oauth providers send the browser back to it
+    pub(crate) api_url: String,
+    // Addresses or networks like "10.0.0.0/8" of reverse proxies, the client address is taken from their X-Forwarded-For
+    pub(crate) trusted_proxies: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "0.0.0.0:6969".to_string(),
+            workers: 2,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Json,
+            api_url: "http://localhost:6969".to_string(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // One JSON object per line, for log collectors
+    #[default]
+    Json,
+    // Readable lines, for local development
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            _ => Err(format!("unknown log format {format}, expected json or text")),
+        }
+    }
+}
+
+/// Where the spans of requests, database queries and storage operations are exported to
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    // The base url of an OpenTelemetry collector accepting OTLP over http, e.g. "http://localhost:4318".
+    // Spans are only exported with it.
+    pub(crate) otlp_endpoint: Option<String>,
+    pub(crate) service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+
+// Used to build absolute links, e.g. in the feeds
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    pub(crate) url: String,
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) image: Option<String>,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        SiteConfig {
+            url: "http://localhost:5173".to_string(),
+            title: "Intelligence Modding".to_string(),
+            description: "News and projects of the Intelligence Modding team".to_string(),
+            image: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // Origins of frontends which may call the api from a browser, e.g. "https://intelligence-modding.de"
+    pub(crate) allowed_origins: Vec<String>,
+    // How long browsers may cache the answer to a preflight request
+    pub(crate) max_age_seconds: u32,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            max_age_seconds: 60 * 60,
+        }
+    }
+}
+
+/// Security headers sent with every response, see the security_headers middleware
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    // Left out when empty. The api only answers with data, so by default nothing may be loaded or framed.
+    pub(crate) content_security_policy: String,
+    // How long browsers only use https for the api, 0 leaves the Strict-Transport-Security header out
+    pub(crate) hsts_max_age_seconds: u64,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        HeadersConfig {
+            content_security_policy: "default-src 'none'; frame-ancestors 'none'".to_string(),
+            hsts_max_age_seconds: 365 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub(crate) host: String,
+    pub(crate) user: String,
+    pub(crate) password: String,
+    pub(crate) database: String,
+    // Defaults to the name of the database, which is what was used before it could be set
+    pub(crate) namespace: Option<String>,
+}
+
+impl DatabaseConfig {
+    pub(crate) fn namespace(&self) -> String {
+        self.namespace.clone().unwrap_or_else(|| self.database.clone())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    // Files in a directory on the server
+    #[default]
+    Filesystem,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "filesystem" => Ok(StorageBackend::Filesystem),
+            _ => Err(format!("unknown storage backend {backend}, expected filesystem")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub(crate) backend: StorageBackend,
+    // The directory of the filesystem backend, relative to the working directory
+    pub(crate) path: String,
+    // Deleted users and posts stay in the trash for this many days before they are removed for good
+    pub(crate) trash_retention_days: i64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Filesystem,
+            path: String::new(),
+            trash_retention_days: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // "HS256" signs with the secret, "RS256" and "EdDSA" with the keys in key_dir
+    #[serde(deserialize_with = "parse")]
+    pub(crate) algorithm: Algorithm,
+    pub(crate) secret: Option<String>,
+    pub(crate) key_dir: PathBuf,
+    // The id of the key in key_dir new tokens are signed with
+    pub(crate) signing_key: Option<String>,
+    // Keep logins in HttpOnly cookies instead of handing the token to the frontend
+    pub(crate) cookie: bool,
+    // Shares the cookies with subdomains, needed if the frontend runs on another one than the api
+    pub(crate) cookie_domain: Option<String>,
+    // Whether people can create accounts themselves: "closed", "invite" or "open"
+    #[serde(deserialize_with = "parse")]
+    pub(crate) registration: RegistrationMode,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            algorithm: Algorithm::HS256,
+            secret: None,
+            key_dir: PathBuf::from("keys"),
+            signing_key: None,
+            cookie: false,
+            cookie_domain: None,
+            registration: RegistrationMode::Closed,
+        }
+    }
+}
+
+/// How long the tokens handed out by the server stay valid
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    // Also the lifetime of the session behind the login
+    pub(crate) login_days: i64,
+    pub(crate) verify_email_hours: i64,
+    pub(crate) reset_password_minutes: i64,
+    // Time to enter the second factor after the password
+    pub(crate) login_challenge_minutes: i64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            login_days: 30,
+            verify_email_hours: 24,
+            reset_password_minutes: 60,
+            login_challenge_minutes: 5,
+        }
+    }
+}
+
+/// Sizes of request bodies
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    pub(crate) max_json_kb: usize,
+    // Images of users and posts
+    pub(crate) max_upload_mb: usize,
+}
+
+impl LimitConfig {
+    pub(crate) fn max_json_bytes(&self) -> usize {
+        self.max_json_kb * 1024
+    }
+
+    pub(crate) fn max_upload_bytes(&self) -> usize {
+        self.max_upload_mb * 1024 * 1024
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            max_json_kb: 256,
+            max_upload_mb: 10,
+        }
+    }
+}
+
+/// When failed logins lock out an account or address, see the lockout module
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    // Failed attempts which are allowed before the backoff kicks in
+    pub(crate) free_account_failures: i64,
+    // Several people can share an address, so they get more attempts than a single account
+    pub(crate) free_address_failures: i64,
+    // The first lockout takes this long, every further failure doubles it
+    pub(crate) base_lock_seconds: i64,
+    pub(crate) max_lock_seconds: i64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            free_account_failures: 5,
+            free_address_failures: 20,
+            base_lock_seconds: 30,
+            max_lock_seconds: 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    // A real mail server
+    Smtp,
+    // Mails are written to a directory or the log, for local development
+    #[default]
+    File,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(transport: &str) -> Result<Self, Self::Err> {
+        match transport {
+            "smtp" => Ok(MailTransport::Smtp),
+            "file" => Ok(MailTransport::File),
+            _ => Err(format!("unknown mail transport {transport}, expected smtp or file")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub(crate) transport: MailTransport,
+    // Where the file transport writes the mails, they are only logged without it
+    pub(crate) dir: Option<PathBuf>,
+    pub(crate) from: Option<String>,
+    pub(crate) smtp_host: Option<String>,
+    pub(crate) smtp_port: u16,
+    pub(crate) smtp_user: Option<String>,
+    pub(crate) smtp_password: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::File,
+            dir: None,
+            from: None,
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_user: None,
+            smtp_password: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthSettings {
+    // What happens to external accounts which aren't linked to a user yet: "existing", "email" or "create"
+    #[serde(deserialize_with = "parse")]
+    pub(crate) accounts: OAuthAccountPolicy,
+    // A provider is enabled by configuring it
+    pub(crate) github: Option<ProviderSettings>,
+    pub(crate) discord: Option<ProviderSettings>,
+}
+
+impl Default for OAuthSettings {
+    fn default() -> Self {
+        OAuthSettings {
+            accounts: OAuthAccountPolicy::Existing,
+            github: None,
+            discord: None,
+        }
+    }
+}
+
+// The urls default to the ones of the provider
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderSettings {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) authorize_url: Option<String>,
+    pub(crate) token_url: Option<String>,
+    pub(crate) api_url: Option<String>,
+}
+
+/// Loads the configuration, the error lists everything which is wrong with it
+pub fn load() -> Result<Config, String> {
+    // The default file is optional, one which was asked for explicitly isn't
+    let (path, required) = match env::var("CONFIG_FILE") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from("config.toml"), false),
+    };
+
+    let mut config: Config = match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content)
+            .map_err(|err| format!("couldn't parse {}: {}", path.display(), err))?,
+        Err(err) if err.kind() == ErrorKind::NotFound && !required => Config::default(),
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    };
+
+    let mut errors = config.apply_env();
+    errors.extend(config.validate());
+
+    if !errors.is_empty() {
+        return Err(format!("invalid configuration:\n  - {}", errors.join("\n  - ")));
+    }
+
+    Ok(config)
+}
+
+impl Config {
+    /// Overrides the settings with the environment variables which are set, returns the ones which couldn't be parsed
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut env = EnvOverrides::default();
+
+        env.set(&mut self.server.bind, "BIND");
+        env.set(&mut self.server.workers, "WORKERS");
+        env.set(&mut self.server.log_level, "RUST_LOG");
+        env.set(&mut self.server.log_format, "LOG_FORMAT");
+        env.set(&mut self.server.api_url, "API_URL");
+        env.set_list(&mut self.server.trusted_proxies, "TRUSTED_PROXIES");
+
+        // The names every OpenTelemetry sdk reads
+        env.set_option(&mut self.tracing.otlp_endpoint, "OTEL_EXPORTER_OTLP_ENDPOINT");
+        env.set(&mut self.tracing.service_name, "OTEL_SERVICE_NAME");
+
+        env.set(&mut self.site.url, "SITE_URL");
+        env.set(&mut self.site.title, "SITE_TITLE");
+        env.set(&mut self.site.description, "SITE_DESCRIPTION");
+        env.set_option(&mut self.site.image, "SITE_IMAGE");
+
+        env.set_list(&mut self.cors.allowed_origins, "CORS_ORIGINS");
+        env.set(&mut self.cors.max_age_seconds, "CORS_MAX_AGE");
+
+        env.set(&mut self.headers.content_security_policy, "CONTENT_SECURITY_POLICY");
+        env.set(&mut self.headers.hsts_max_age_seconds, "HSTS_MAX_AGE");
+
+        env.set(&mut self.database.host, "DB_HOST");
+        env.set(&mut self.database.user, "DB_USER");
+        env.set(&mut self.database.password, "DB_PASS");
+        env.set(&mut self.database.database, "DB_DATABASE");
+        env.set_option(&mut self.database.namespace, "DB_NAMESPACE");
+
+        env.set(&mut self.storage.backend, "STORAGE_BACKEND");
+        env.set(&mut self.storage.path, "FILE_PATH");
+        env.set(&mut self.storage.trash_retention_days, "TRASH_RETENTION_DAYS");
+
+        env.set(&mut self.auth.algorithm, "JWT_ALGORITHM");
+        env.set_option(&mut self.auth.secret, "JWT_SECRET");
+        env.set(&mut self.auth.key_dir, "JWT_KEY_DIR");
+        env.set_option(&mut self.auth.signing_key, "JWT_SIGNING_KEY");
+        env.set(&mut self.auth.cookie, "AUTH_COOKIE");
+        env.set_option(&mut self.auth.cookie_domain, "AUTH_COOKIE_DOMAIN");
+        env.set(&mut self.auth.registration, "REGISTRATION");
+
+        env.set(&mut self.tokens.login_days, "LOGIN_TOKEN_DAYS");
+        env.set(&mut self.tokens.verify_email_hours, "VERIFY_EMAIL_TOKEN_HOURS");
+        env.set(&mut self.tokens.reset_password_minutes, "RESET_PASSWORD_TOKEN_MINUTES");
+        env.set(&mut self.tokens.login_challenge_minutes, "LOGIN_CHALLENGE_MINUTES");
+
+        env.set(&mut self.limits.max_json_kb, "MAX_JSON_KB");
+        env.set(&mut self.limits.max_upload_mb, "MAX_UPLOAD_MB");
+
+        env.set(&mut self.lockout.free_account_failures, "LOCKOUT_FREE_ACCOUNT_FAILURES");
+        env.set(&mut self.lockout.free_address_failures, "LOCKOUT_FREE_ADDRESS_FAILURES");
+        env.set(&mut self.lockout.base_lock_seconds, "LOCKOUT_BASE_SECONDS");
+        env.set(&mut self.lockout.max_lock_seconds, "LOCKOUT_MAX_SECONDS");
+
+        env.set(&mut self.mail.transport, "MAIL_TRANSPORT");
+        env.set_option(&mut self.mail.dir, "MAIL_DIR");
+        env.set_option(&mut self.mail.from, "MAIL_FROM");
+        env.set_option(&mut self.mail.smtp_host, "SMTP_HOST");
+        env.set(&mut self.mail.smtp_port, "SMTP_PORT");
+        env.set_option(&mut self.mail.smtp_user, "SMTP_USER");
+        env.set_option(&mut self.mail.smtp_password, "SMTP_PASS");
+
+        env.set(&mut self.oauth.accounts, "OAUTH_ACCOUNTS");
+        for (provider, prefix) in [(&mut self.oauth.github, "GITHUB"), (&mut self.oauth.discord, "DISCORD")] {
+            // Setting the client id enables a provider which isn't in the file
+            if let Ok(client_id) = env::var(format!("{prefix}_CLIENT_ID")) {
+                provider.get_or_insert_with(ProviderSettings::default).client_id = client_id;
+            }
+
+            if let Some(provider) = provider {
+                env.set(&mut provider.client_secret, &format!("{prefix}_CLIENT_SECRET"));
+                env.set_option(&mut provider.authorize_url, &format!("{prefix}_AUTHORIZE_URL"));
+                env.set_option(&mut provider.token_url, &format!("{prefix}_TOKEN_URL"));
+                env.set_option(&mut provider.api_url, &format!("{prefix}_API_URL"));
+            }
+        }
+
+        env.errors
+    }
+
+    // Everything which would otherwise only fail once it is used
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, message: &str| {
+            if !valid {
+                errors.push(message.to_string());
+            }
+        };
+
+        check(self.server.workers > 0, "server.workers has to be at least 1");
+        check(self.server.bind.to_socket_addrs().is_ok(), "server.bind (BIND) has to be an address like 0.0.0.0:6969");
+        check(is_http_url(&self.server.api_url), "server.api_url (API_URL) has to be an http or https url");
+        check(self.server.trusted_proxies.iter().all(|proxy| parse_network(proxy).is_some()),
+            "server.trusted_proxies (TRUSTED_PROXIES) have to be addresses or networks like 10.0.0.0/8");
+        check(self.tracing.otlp_endpoint.as_deref().is_none_or(is_http_url),
+            "tracing.otlp_endpoint (OTEL_EXPORTER_OTLP_ENDPOINT) has to be an http or https url");
+        check(is_http_url(&self.site.url), "site.url (SITE_URL) has to be an http or https url");
+        check(self.cors.allowed_origins.iter().all(|origin| is_http_url(origin) && !origin.ends_with('/')),
+            "cors.allowed_origins (CORS_ORIGINS) have to be http or https origins without a trailing slash");
+        check(HeaderValue::from_str(&self.headers.content_security_policy).is_ok(),
+            "headers.content_security_policy (CONTENT_SECURITY_POLICY) can't be sent as a header");
+
+        check(!self.database.host.is_empty(), "database.host (DB_HOST) is missing");
+        check(!self.database.user.is_empty(), "database.user (DB_USER) is missing");
+        check(!self.database.password.is_empty(), "database.password (DB_PASS) is missing");
+        check(!self.database.database.is_empty(), "database.database (DB_DATABASE) is missing");
+
+        check(!self.storage.path.is_empty(), "storage.path (FILE_PATH) is missing");
+        check(self.storage.trash_retention_days > 0 && self.storage.trash_retention_days <= MAX_DAYS,
+            "storage.trash_retention_days (TRASH_RETENTION_DAYS) has to be positive and at most ten years");
+
+        match self.auth.algorithm {
+            Algorithm::HS256 => check(self.auth.secret.as_ref().is_some_and(|secret| !secret.is_empty()),
+                "auth.secret (JWT_SECRET) is missing, it is needed for HS256"),
+            Algorithm::RS256 | Algorithm::EdDSA => check(self.auth.signing_key.is_some(),
+                "auth.signing_key (JWT_SIGNING_KEY) is missing, it is needed for RS256 and EdDSA"),
+            _ => check(false, "auth.algorithm (JWT_ALGORITHM) has to be HS256, RS256 or EdDSA"),
+        }
+
+        let tokens = &self.tokens;
+        check((1..=MAX_DAYS).contains(&tokens.login_days)
+            && (1..=MAX_DAYS * 24).contains(&tokens.verify_email_hours)
+            && (1..=MAX_DAYS * 24 * 60).contains(&tokens.reset_password_minutes)
+            && (1..=MAX_DAYS * 24 * 60).contains(&tokens.login_challenge_minutes),
+            "the token lifetimes have to be positive and at most ten years");
+
+        check(self.limits.max_json_kb > 0 && self.limits.max_upload_mb > 0, "the limits have to be positive");
+
+        check(self.lockout.free_account_failures > 0 && self.lockout.free_address_failures > 0,
+            "the free lockout failures have to be positive");
+        check(self.lockout.base_lock_seconds > 0 && self.lockout.base_lock_seconds <= self.lockout.max_lock_seconds,
+            "lockout.base_lock_seconds has to be positive and at most lockout.max_lock_seconds");
+        check(self.lockout.max_lock_seconds <= MAX_LOCK_SECONDS, "lockout.max_lock_seconds can be at most a year");
+
+        if self.mail.transport == MailTransport::Smtp {
+            check(self.mail.smtp_host.is_some(), "mail.smtp_host (SMTP_HOST) is missing, it is needed for smtp");
+            check(self.mail.from.is_some(), "mail.from (MAIL_FROM) is missing, it is needed for smtp");
+        }
+
+        for (provider, name) in [(&self.oauth.github, "github"), (&self.oauth.discord, "discord")] {
+            if let Some(provider) = provider {
+                check(!provider.client_id.is_empty() && !provider.client_secret.is_empty(),
+                    &format!("oauth.{name} needs a client_id and a client_secret"));
+            }
+        }
+
+        errors
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// Collects the environment variables which couldn't be parsed, so they are reported together
+#[derive(Default)]
+struct EnvOverrides {
+    errors: Vec<String>,
+}
+
+impl EnvOverrides {
+    fn set<T: FromStr>(&mut self, setting: &mut T, key: &str) where T::Err: Display {
+        if let Some(value) = self.parse(key) {
+            *setting = value;
+        }
+    }
+
+    fn set_option<T: FromStr>(&mut self, setting: &mut Option<T>, key: &str) where T::Err: Display {
+        if let Some(value) = self.parse(key) {
+            *setting = Some(value);
+        }
+    }
+
+    // A comma separated list
+    fn set_list(&mut self, setting: &mut Vec<String>, key: &str) {
+        if let Ok(value) = env::var(key) {
+            *setting = value.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect();
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str) -> Option<T> where T::Err: Display {
+        let value = env::var(key).ok()?;
+
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(format!("couldn't interpret {key}: {err}"));
+                None
+            }
+        }
+    }
+}
+
+// For settings which are written as strings but have a FromStr implementation
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_error(config: &Config, message: &str) -> bool {
+        config.validate().iter().any(|error| error.contains(message))
+    }
+
+    // A config which passes the validation, the tests change one setting at a time
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.database.host = "localhost:8000".to_string();
+        config.database.user = "root".to_string();
+        config.database.password = "root".to_string();
+        config.database.database = "intelligence".to_string();
+        config.storage.path = "files".to_string();
+        config.auth.secret = Some("secret".to_string());
+        config
+    }
+
+    #[test]
+    fn valid_config_has_no_errors() {
+        assert_eq!(valid_config().validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn missing_settings_are_reported() {
+        let config = Config::default();
+        assert!(has_error(&config, "database.host (DB_HOST) is missing"));
+        assert!(has_error(&config, "auth.secret (JWT_SECRET) is missing"));
+    }
+
+    #[test]
+    fn token_lifetimes_are_bounded() {
+        let message = "the token lifetimes have to be positive and at most ten years";
+
+        let mut config = valid_config();
+        config.tokens.login_days = 0;
+        assert!(has_error(&config, message));
+
+        // chrono::Duration::days panics for these, they have to be caught before
+        let mut config = valid_config();
+        config.tokens.login_days = i64::MAX;
+        assert!(has_error(&config, message));
+
+        let mut config = valid_config();
+        config.tokens.reset_password_minutes = i64::MAX;
+        assert!(has_error(&config, message));
+
+        let mut config = valid_config();
+        config.tokens.login_days = MAX_DAYS;
+        assert!(!has_error(&config, message));
+    }
+
+    #[test]
+    fn trash_retention_is_bounded() {
+        let mut config = valid_config();
+        config.storage.trash_retention_days = i64::MAX;
+        assert!(has_error(&config, "storage.trash_retention_days"));
+    }
+
+    #[test]
+    fn env_overrides_are_parsed() {
+        // Names nothing else reads, tests run in parallel
+        env::set_var("CONFIG_TEST_NUMBER", "42");
+        env::set_var("CONFIG_TEST_LIST", " https://a.example , ,https://b.example");
+        env::set_var("CONFIG_TEST_BROKEN", "forty-two");
+
+        let mut env = EnvOverrides::default();
+        let mut number = 1i64;
+        let mut list = Vec::new();
+        let mut option: Option<String> = None;
+        let mut broken = 1i64;
+
+        env.set(&mut number, "CONFIG_TEST_NUMBER");
+        env.set_list(&mut list, "CONFIG_TEST_LIST");
+        env.set_option(&mut option, "CONFIG_TEST_UNSET");
+        env.set(&mut broken, "CONFIG_TEST_BROKEN");
+
+        assert_eq!(number, 42);
+        assert_eq!(list, vec!["https://a.example".to_string(), "https://b.example".to_string()]);
+        assert_eq!(option, None);
+        // Broken values keep the setting and are reported
+        assert_eq!(broken, 1);
+        assert_eq!(env.errors.len(), 1);
+        assert!(env.errors[0].starts_with("couldn't interpret CONFIG_TEST_BROKEN"));
+    }
+
+    #[test]
+    fn lock_longer_than_a_year_is_refused() {
+        let mut config = Config::default();
+        assert!(!has_error(&config, "lockout.max_lock_seconds"));
+
+        config.lockout.max_lock_seconds = i64::MAX;
+        assert!(has_error(&config, "lockout.max_lock_seconds can be at most a year"));
+    }
+}
@@ -0,0 +1,98 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use chrono::Duration;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use crate::error::ApiError;
+
+pub(crate) const SESSION_COOKIE: &str = "session";
+// Readable by the frontend, which sends it back in the CSRF_HEADER
+pub(crate) const CSRF_COOKIE: &str = "csrf_token";
+pub(crate) const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Keeps the login token in an HttpOnly cookie instead of handing it to the frontend
+///
+/// Browsers send the cookie along with every request, also with the ones other sites trigger.
+/// Requests which change something therefore need a double-submit CSRF token: a random value which is
+/// set as a second cookie at login and has to be repeated in the `X-CSRF-Token` header. Other sites can't
+/// read the cookie, so they can't send the header.
+#[derive(Clone)]
+pub(crate) struct CookieAuth {
+    // Needed when the frontend runs on another subdomain than the api, it has to read the csrf cookie
+    domain: Option<String>,
+    // The cookies expire together with the login
+    lifetime: Duration,
+}
+
+impl CookieAuth {
+    pub(crate) fn new(domain: Option<String>, lifetime: Duration) -> Self {
+        CookieAuth {
+            domain,
+            lifetime,
+        }
+    }
+
+    /// The cookies which carry a login, together with the csrf token in them
+    pub(crate) fn login_cookies(&self, token: String) -> (String, [Cookie<'static>; 2]) {
+        let csrf_token: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let session = self.cookie(SESSION_COOKIE, token, true);
+        let csrf = self.cookie(CSRF_COOKIE, csrf_token.clone(), false);
+
+        (csrf_token, [session, csrf])
+    }
+
+    pub(crate) fn logout_cookies(&self) -> [Cookie<'static>; 2] {
+        [SESSION_COOKIE, CSRF_COOKIE].map(|name| {
+            let mut cookie = self.cookie(name, String::new(), false);
+            cookie.make_removal();
+            cookie
+        })
+    }
+
+    fn cookie(&self, name: &'static str, value: String, http_only: bool) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .path("/")
+            .http_only(http_only)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(CookieDuration::seconds(self.lifetime.num_seconds()))
+            .finish();
+
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        cookie
+    }
+}
+
+pub(crate) fn token_from_cookie(req: &HttpRequest) -> Option<String> {
+    req.cookie(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Fails with [`ApiError::InvalidCsrfToken`] if a request which changes something doesn't repeat the csrf cookie
+pub(crate) fn check_csrf(req: &HttpRequest) -> Result<(), ApiError> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+
+    let cookie = req.cookie(CSRF_COOKIE).ok_or(ApiError::InvalidCsrfToken)?;
+    let header = req.headers().get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(ApiError::InvalidCsrfToken)?;
+
+    if cookie.value().is_empty() || cookie.value() != header {
+        return Err(ApiError::InvalidCsrfToken);
+    }
+
+    Ok(())
+}
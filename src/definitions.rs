@@ -1,8 +1,13 @@
 use std::cmp::PartialEq;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use surrealdb::Datetime;
 use surrealdb::sql::Id;
+use utoipa::ToSchema;
+use crate::error::ApiError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IntelliThing {
@@ -21,65 +26,418 @@ impl PartialEq for IntelliThing {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct User {
     #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
     pub(crate) id: IntelliThing,
     pub(crate) admin: bool,
     pub(crate) name: String,
     pub(crate) email: String,
+    #[serde(default)]
+    pub(crate) email_verified: bool,
     #[serde(skip_serializing)]
     pub(crate) password: String,
+    // Maintained by the database and set by every reset, tokens issued before this can't reset the password anymore
+    #[serde(skip_serializing)]
+    pub(crate) password_changed: Option<DateTime<Utc>>,
+    // Whether logging in needs a code of an authenticator app, see the totp module
+    #[serde(default)]
+    pub(crate) two_factor: bool,
+    #[serde(skip_serializing)]
+    pub(crate) totp_secret: Option<String>,
+    // Secret of an enrolment which hasn't been confirmed yet
+    #[serde(skip_serializing)]
+    pub(crate) totp_pending: Option<String>,
+    // The recovery codes and the last used time step are only ever checked by the database
+    pub(crate) firstname: Option<String>,
+    pub(crate) lastname: Option<String>,
+    pub(crate) display_name: Option<String>,
+    pub(crate) bio: Option<String>,
+    // Social links, keyed by the name of the platform e.g. "github"
+    #[serde(default)]
+    pub(crate) links: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) visibility: ProfileVisibility,
+    // Set while the user is in the trash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+    // The session the request was authenticated with, not set for personal access tokens
+    #[serde(skip)]
+    pub(crate) session: Option<String>,
+}
+
+// Which parts of a user are shown on their public profile
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(default)]
+pub struct ProfileVisibility {
+    pub(crate) display_name: bool,
+    pub(crate) firstname: bool,
+    pub(crate) lastname: bool,
+    pub(crate) bio: bool,
+    pub(crate) avatar: bool,
+    pub(crate) links: bool,
+    pub(crate) posts: bool,
+}
+
+impl Default for ProfileVisibility {
+    // Real names are opt-in, everything else is public unless hidden
+    fn default() -> Self {
+        ProfileVisibility {
+            display_name: true,
+            firstname: false,
+            lastname: false,
+            bio: true,
+            avatar: true,
+            links: true,
+            posts: true,
+        }
+    }
+}
+
+// The public view of a user, only contains the fields the user made visible
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PublicProfile {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) display_name: Option<String>,
     pub(crate) firstname: Option<String>,
     pub(crate) lastname: Option<String>,
+    pub(crate) bio: Option<String>,
+    pub(crate) avatar: Option<String>,
+    pub(crate) links: BTreeMap<String, String>,
+    pub(crate) posts: Vec<Post>,
+    pub(crate) projects: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct Post {
     #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
     pub(crate) id: IntelliThing,
-    #[serde(serialize_with = "serialize_record_id")]
-    pub(crate) author: IntelliThing,
+    // Ordered by the position on the `wrote` relation, filled in by the queries
+    #[serde(serialize_with = "serialize_record_ids", default)]
+    #[schema(value_type = Vec<String>)]
+    pub(crate) authors: Vec<IntelliThing>,
     pub(crate) likes: i32,
     pub(crate) views: i32,
     pub(crate) title: String,
-    pub(crate) posted: Datetime,
+    pub(crate) summary: Option<String>,
+    pub(crate) content: Option<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    pub(crate) project: Option<String>,
+    pub(crate) posted: DateTime<Utc>,
+    // Set while the post is in the trash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
 }
 
-impl User {
-    pub fn validate_password(&self, password: String) -> bool {
-        // TODO implement hashing
-        self.password == password
+// Snapshot of a post, created by the database every time the post is created or its content changes
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct PostRevision {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) post: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) editor: IntelliThing,
+    #[schema(value_type = String, format = DateTime)]
+    pub(crate) created: Datetime,
+    pub(crate) title: String,
+    pub(crate) summary: Option<String>,
+    pub(crate) content: Option<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    pub(crate) project: Option<String>,
+    #[serde(serialize_with = "serialize_option_record_id")]
+    #[schema(value_type = Option<String>)]
+    pub(crate) restored_from: Option<IntelliThing>,
+}
+
+// A single line of the diff between two revisions
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DiffLine {
+    pub(crate) change: String,
+    pub(crate) old_line: Option<usize>,
+    pub(crate) new_line: Option<usize>,
+    pub(crate) value: String,
+}
+
+// A published post joined with the names of its authors, used to render the feeds
+#[derive(Deserialize, Debug)]
+pub struct FeedPost {
+    pub(crate) id: IntelliThing,
+    pub(crate) title: String,
+    pub(crate) summary: Option<String>,
+    #[serde(default)]
+    pub(crate) author_names: Vec<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    pub(crate) posted: DateTime<Utc>,
+    // The last edit, or `posted` if it wasn't edited since it was published
+    pub(crate) updated: DateTime<Utc>,
+}
+
+// An entry of the audit log, entries can't be changed once they are written
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct AuditEntry {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) actor: IntelliThing,
+    pub(crate) action: String,
+    // The affected record, e.g. "post:abc"
+    pub(crate) target: String,
+    pub(crate) before: Option<serde_json::Value>,
+    pub(crate) after: Option<serde_json::Value>,
+    pub(crate) ip: Option<String>,
+    pub(crate) created: DateTime<Utc>,
+}
+
+// An account at an external login provider linked to a user
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Identity {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    pub(crate) provider: String,
+    // The id of the account at the provider
+    pub(crate) subject: String,
+    pub(crate) username: String,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) user: IntelliThing,
+    pub(crate) created: DateTime<Utc>,
+}
+
+// A login of a user, its id is the sid claim of the login token
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Session {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) user: IntelliThing,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) ip: Option<String>,
+    pub(crate) created: DateTime<Utc>,
+    pub(crate) last_seen: DateTime<Utc>,
+    pub(crate) expires: DateTime<Utc>,
+}
+
+// A personal access token, the token itself is only known to the user
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ApiToken {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) user: IntelliThing,
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<String>,
+    // The start of the token, so the user can tell their tokens apart
+    pub(crate) prefix: String,
+    pub(crate) created: DateTime<Utc>,
+    pub(crate) expires: Option<DateTime<Utc>>,
+    pub(crate) last_used: Option<DateTime<Utc>>,
+}
+
+// Used by the http endpoint to create personal access tokens
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BodyApiToken {
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) expires: Option<DateTime<Utc>>,
+}
+
+// Failed logins of a user, the id of the record is the id of the user
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Lockout {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    pub(crate) failures: i64,
+    pub(crate) last_failure: DateTime<Utc>,
+    pub(crate) locked_until: Option<DateTime<Utc>>,
+}
+
+// Who can create an account without an admin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationMode {
+    Closed,
+    InviteOnly,
+    Open,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "closed" => Ok(RegistrationMode::Closed),
+            "invite" => Ok(RegistrationMode::InviteOnly),
+            "open" => Ok(RegistrationMode::Open),
+            _ => Err(format!("unknown registration mode {mode}, expected closed, invite or open")),
+        }
     }
+}
+
+// A code which lets people register, the code is the id of the record
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Invite {
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) id: IntelliThing,
+    #[serde(serialize_with = "serialize_record_id")]
+    #[schema(value_type = String)]
+    pub(crate) created_by: IntelliThing,
+    pub(crate) created: DateTime<Utc>,
+    pub(crate) expires: Option<DateTime<Utc>>,
+    // No limit if not set
+    pub(crate) max_uses: Option<i64>,
+    pub(crate) uses: i64,
+    // Whether the accounts registered with this invite are admins
+    pub(crate) admin: bool,
+}
+
+// Used by the http endpoint to create invites
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BodyInvite {
+    pub(crate) expires: Option<DateTime<Utc>>,
+    // Single use unless given, null for an unlimited invite
+    #[serde(default = "default_max_uses")]
+    pub(crate) max_uses: Option<i64>,
+    #[serde(default)]
+    pub(crate) admin: bool,
+}
+
+fn default_max_uses() -> Option<i64> {
+    Some(1)
+}
+
+// Used by the http endpoint to register a new account
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct Registration {
+    pub(crate) name: String,
+    pub(crate) email: String,
+    pub(crate) password: String,
+    pub(crate) invite: Option<String>,
+}
+
+// Public information about the site, used wherever we need to build absolute links
+#[derive(Clone, Debug)]
+pub struct SiteInfo {
+    pub(crate) url: String,
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) image: Option<String>,
+}
 
+// A project mentioned by at least one published post, with the date of its newest post
+#[derive(Deserialize, Debug)]
+pub struct ProjectSummary {
+    pub(crate) project: String,
+    pub(crate) updated: DateTime<Utc>,
+}
+
+// OpenGraph and Twitter card data for a page of the frontend
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PageMeta {
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) url: String,
+    pub(crate) image: Option<String>,
+    pub(crate) open_graph: BTreeMap<String, String>,
+    pub(crate) twitter: BTreeMap<String, String>,
+}
+
+impl User {
     // Checks if one of the unique values is the value to compare
     pub fn compare(&self,to_compare: &String) -> bool {
         self.id == IntelliThing {id: Id::from(to_compare) } || self.name == to_compare.clone() || self.email == to_compare.clone()
     }
+
+    pub fn require_admin(&self) -> Result<(), ApiError> {
+        if !self.admin {
+            return Err(ApiError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Admins can act on every user, everyone else only on themselves
+    pub fn require_self_or_admin(&self, user_id: &String) -> Result<(), ApiError> {
+        if !self.admin && !self.compare(user_id) {
+            return Err(ApiError::PermissionDenied);
+        }
+        Ok(())
+    }
 }
 
 
+impl Post {
+    pub fn is_author(&self, user: &User) -> bool {
+        self.authors.contains(&user.id)
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.posted <= Utc::now()
+    }
+
+    // Admins can edit every post, everyone else only the posts they are an author of
+    pub fn require_editor(&self, user: &User) -> Result<(), ApiError> {
+        if !user.admin && !self.is_author(user) {
+            return Err(ApiError::PermissionDenied);
+        }
+        Ok(())
+    }
+}
+
 // Used by the http endpoint to allow patching the user
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct BodyUser {
     #[serde(serialize_with = "serialize_option_record_id", deserialize_with = "deserialize_record_id")]
+    #[schema(value_type = Option<String>)]
     pub(crate) id: Option<IntelliThing>,
     pub(crate) name: Option<String>,
     pub(crate) email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) email_verified: Option<bool>,
     pub(crate) admin: Option<bool>,
     pub(crate) password: Option<String>,
     pub(crate) firstname: Option<String>,
     pub(crate) lastname: Option<String>,
+    pub(crate) display_name: Option<String>,
+    pub(crate) bio: Option<String>,
+    pub(crate) links: Option<BTreeMap<String, String>>,
+    pub(crate) visibility: Option<ProfileVisibility>,
 }
 
 // Used by the http endpoint to allow patching the post
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct BodyPost {
-    #[serde(serialize_with = "serialize_option_record_id", deserialize_with = "deserialize_record_id")]
-    pub(crate) author: Option<IntelliThing>,
+    // Ids of the authors, in the order they should be credited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) authors: Option<Vec<String>>,
     pub(crate) likes: Option<i32>,
     pub(crate) views: Option<i32>,
     pub(crate) title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) project: Option<String>,
+    #[schema(value_type = Option<String>, format = DateTime)]
     pub(crate) posted: Option<Datetime>,
 }
 
@@ -90,6 +448,13 @@ fn serialize_record_id<S>(record_id: &IntelliThing, serializer: S) -> Result<S::
     serializer.serialize_str(&record_id.id.to_string())
 }
 
+fn serialize_record_ids<S>(record_ids: &[IntelliThing], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(record_ids.iter().map(|record_id| record_id.id.to_string()))
+}
+
 fn serialize_option_record_id<S>(record_id: &Option<IntelliThing>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
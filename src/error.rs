@@ -0,0 +1,250 @@
+use std::fmt;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use jsonwebtoken::errors::ErrorKind as JwtErrorKind;
+use tracing::error;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::middleware::request_id::current_request_id;
+
+const AUTH_REALM: &str = "intelligence";
+
+/// Every error a handler can answer with
+///
+/// Each variant maps to a status code and a machine-readable code, which is sent to the client
+/// together with a message and the id of the request.
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound,
+    UserNotFound,
+    PostNotFound,
+    RevisionNotFound,
+    BadRequest(String),
+    PayloadTooLarge,
+    Conflict(String),
+    InvalidInvite,
+    RegistrationClosed,
+    AccountNotLinked,
+    Provider(String),
+    InvalidCredentials,
+    InvalidCode,
+    MissingToken,
+    InvalidToken,
+    TokenExpired,
+    PermissionDenied,
+    InvalidCsrfToken,
+    // Seconds until the client may try again
+    TooManyRequests(u64),
+    // Seconds until the client should try again
+    DatabaseUnavailable(u64),
+    Database(Box<surrealdb::Error>),
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+    Internal(String),
+}
+
+// The body of every error response
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound => "not_found",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::PostNotFound => "post_not_found",
+            ApiError::RevisionNotFound => "revision_not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::InvalidInvite => "invalid_invite",
+            ApiError::RegistrationClosed => "registration_closed",
+            ApiError::AccountNotLinked => "account_not_linked",
+            ApiError::Provider(_) => "provider_error",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidCode => "invalid_code",
+            ApiError::MissingToken => "missing_token",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::TokenExpired => "token_expired",
+            ApiError::PermissionDenied => "permission_denied",
+            ApiError::InvalidCsrfToken => "invalid_csrf_token",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::DatabaseUnavailable(_) => "database_unavailable",
+            ApiError::Database(_) => "database_error",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    // The WWW-Authenticate challenge as described in RFC 6750 section 3.
+    // Requests without any credentials don't get an error code, see section 3.1.
+    fn challenge(&self) -> Option<String> {
+        let error = match self {
+            ApiError::MissingToken | ApiError::InvalidCredentials | ApiError::InvalidCode => None,
+            ApiError::InvalidToken | ApiError::TokenExpired => Some("invalid_token"),
+            ApiError::PermissionDenied => Some("insufficient_scope"),
+            _ => return None,
+        };
+
+        Some(match error {
+            Some(error) => format!("Bearer realm=\"{}\", error=\"{}\", error_description=\"{}\"", AUTH_REALM, error, self.message()),
+            None => format!("Bearer realm=\"{}\"", AUTH_REALM),
+        })
+    }
+
+    // The message sent to the client. Server errors only get a generic one, the details end up in the log.
+    fn message(&self) -> String {
+        match self {
+            ApiError::NotFound => "The requested resource does not exist".to_string(),
+            ApiError::UserNotFound => "The user does not exist".to_string(),
+            ApiError::PostNotFound => "The post does not exist".to_string(),
+            ApiError::RevisionNotFound => "The revision does not exist".to_string(),
+            ApiError::BadRequest(message) => message.clone(),
+            ApiError::PayloadTooLarge => "The request body is too large".to_string(),
+            ApiError::Conflict(message) => message.clone(),
+            ApiError::InvalidInvite => "The invite code is invalid, expired or used up".to_string(),
+            ApiError::RegistrationClosed => "Registration is closed".to_string(),
+            ApiError::AccountNotLinked => "No account is linked to this login".to_string(),
+            ApiError::Provider(_) => "The login provider could not process the request".to_string(),
+            ApiError::InvalidCredentials => "Invalid username or password".to_string(),
+            ApiError::InvalidCode => "The two-factor code is invalid or was already used".to_string(),
+            ApiError::MissingToken => "No token was provided".to_string(),
+            ApiError::InvalidToken => "The token is invalid".to_string(),
+            ApiError::TokenExpired => "The token has expired".to_string(),
+            ApiError::PermissionDenied => "You are not allowed to do this".to_string(),
+            ApiError::InvalidCsrfToken => "The CSRF token is missing or doesn't match".to_string(),
+            ApiError::TooManyRequests(retry_after) => format!("Too many failed attempts, try again in {} seconds", retry_after),
+            ApiError::DatabaseUnavailable(retry_after) => format!("The database is currently unavailable, try again in {} seconds", retry_after),
+            ApiError::Database(_) => "The database could not process the request".to_string(),
+            ApiError::Storage(_) => "The storage could not process the request".to_string(),
+            ApiError::Internal(_) => "Something went wrong on our side".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Database(err) => write!(f, "database error: {}", err),
+            ApiError::Storage(err) => write!(f, "storage error: {}", err),
+            ApiError::Internal(message) => write!(f, "internal error: {}", message),
+            ApiError::Provider(message) => write!(f, "provider error: {}", message),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound | ApiError::UserNotFound | ApiError::PostNotFound | ApiError::RevisionNotFound => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) | ApiError::InvalidInvite => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::InvalidCredentials | ApiError::InvalidCode | ApiError::MissingToken | ApiError::InvalidToken | ApiError::TokenExpired => StatusCode::UNAUTHORIZED,
+            ApiError::PermissionDenied | ApiError::InvalidCsrfToken | ApiError::RegistrationClosed | ApiError::AccountNotLinked => StatusCode::FORBIDDEN,
+            ApiError::Provider(_) => StatusCode::BAD_GATEWAY,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::DatabaseUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(_) | ApiError::Storage(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let request_id = current_request_id();
+
+        // An outage of the database is logged once by the database manager, not for every request
+        if self.status_code().is_server_error() && !matches!(self, ApiError::DatabaseUnavailable(_)) {
+            // The request id is part of the request span
+            error!(error = %self, "Request failed");
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(challenge) = self.challenge() {
+            response.insert_header((WWW_AUTHENTICATE, challenge));
+        }
+        if let ApiError::TooManyRequests(retry_after) | ApiError::DatabaseUnavailable(retry_after) = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id,
+        })
+    }
+}
+
+/// Lets a middleware add its headers to the response of an error coming from further in
+///
+/// Errors are only turned into responses by the server, after every middleware has returned, so the response is
+/// rendered here already and handed on inside the error.
+pub fn add_error_headers(err: actix_web::Error, add_headers: impl FnOnce(&mut HeaderMap)) -> actix_web::Error {
+    let mut response = err.error_response();
+    add_headers(response.headers_mut());
+    InternalError::from_response(err, response).into()
+}
+
+impl From<surrealdb::Error> for ApiError {
+    fn from(err: surrealdb::Error) -> Self {
+        // The unique indexes on users catch what the check before the write missed, e.g. two registrations at once.
+        // Over the websocket only the message of the error is left to recognize them by.
+        let message = err.to_string();
+        if message.contains("index `user_name` already contains") || message.contains("index `user_email` already contains") {
+            return ApiError::Conflict("The name or email is already taken".to_string());
+        }
+
+        ApiError::Database(Box::new(err))
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for ApiError {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        ApiError::Storage(err)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApiError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            JwtErrorKind::ExpiredSignature => ApiError::TokenExpired,
+            _ => ApiError::InvalidToken,
+        }
+    }
+}
+
+impl From<actix_web::error::PayloadError> for ApiError {
+    fn from(err: actix_web::error::PayloadError) -> Self {
+        ApiError::BadRequest(format!("Could not read the request body: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::error::Api;
+    use super::*;
+
+    fn query_error(message: &str) -> surrealdb::Error {
+        surrealdb::Error::Api(Api::Query(message.to_string()))
+    }
+
+    #[test]
+    fn taken_name_or_email_is_a_conflict() {
+        let err = ApiError::from(query_error("Database index `user_name` already contains 'alice', with record `user:a`"));
+        assert!(matches!(err, ApiError::Conflict(_)));
+
+        let err = ApiError::from(query_error("Database index `user_email` already contains 'a@example.com', with record `user:a`"));
+        assert!(matches!(err, ApiError::Conflict(_)));
+    }
+
+    #[test]
+    fn other_errors_stay_database_errors() {
+        let err = ApiError::from(query_error("Database index `wrote_unique` already contains [user:a, post:b], with record `wrote:c`"));
+        assert!(matches!(err, ApiError::Database(_)));
+    }
+}
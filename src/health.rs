@@ -0,0 +1,50 @@
+use actix_web::{get, HttpResponse, web};
+use tracing::warn;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::storage::database_manager::DatabaseManager;
+use crate::storage::storage_manager::StorageManager;
+
+#[derive(Serialize, ToSchema)]
+struct Readiness {
+    database: bool,
+    storage: bool,
+}
+
+// Neither endpoint needs a login or the database, so the orchestrator can always reach them
+pub fn health_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz_get)
+        .service(readyz_get);
+}
+
+// The process is up and handles requests
+#[utoipa::path(tag = "operations", responses((status = 200, description = "The process is up")))]
+#[get("/healthz")]
+async fn healthz_get() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Whether requests can be served, answered with 503 until the database and the storage work
+#[utoipa::path(tag = "operations", responses((status = 200, body = Readiness), (status = 503, body = Readiness)))]
+#[get("/readyz")]
+async fn readyz_get(
+    database_manager: web::Data<DatabaseManager>,
+    storage_manager: web::Data<StorageManager>) -> HttpResponse {
+
+    let (database, storage) = tokio::join!(database_manager.ping(), storage_manager.check_writable());
+
+    if let Err(err) = &storage {
+        warn!("The storage isn't writable: {}", err);
+    }
+
+    let readiness = Readiness {
+        database,
+        storage: storage.is_ok(),
+    };
+
+    if readiness.database && readiness.storage {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use actix_web::{get, HttpResponse, web};
+use actix_web::http::header::{CacheControl, CacheDirective};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::traits::PublicKeyParts;
+
+/// The keys tokens are signed and verified with
+///
+/// With a shared secret (HS256) there is a single key without an id. With RS256 or EdDSA every key has an id,
+/// the `kid` in the header of a token picks the key it is verified with, so several keys can be valid at once.
+///
+/// The asymmetric keys are read from a directory: `{kid}.pub.pem` is a public key tokens are accepted from,
+/// `{kid}.pem` the private key of the key new tokens are signed with. To rotate the signing key:
+/// 1. put the public key of the new key into the directory and restart, so every instance accepts it
+/// 2. put its private key there as well and make it the signing key
+/// 3. remove the old key once the tokens signed with it have expired, which takes as long as `tokens.login_days`
+#[derive(Clone)]
+pub(crate) struct JwtKeys {
+    algorithm: Algorithm,
+    // The key new tokens are signed with
+    kid: Option<String>,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<Option<String>, DecodingKey>,
+    // The public keys, empty for a shared secret
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    pub(crate) fn from_secret(secret: &[u8]) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            kid: None,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_keys: HashMap::from([(None, DecodingKey::from_secret(secret))]),
+            jwks: JwkSet { keys: Vec::new() },
+        }
+    }
+
+    /// Loads the keys in `directory`, new tokens are signed with the key `signing_kid`
+    pub(crate) fn from_directory(algorithm: Algorithm, directory: &Path, signing_kid: &str) -> Result<Self, String> {
+        let entries = fs::read_dir(directory)
+            .map_err(|err| format!("couldn't read {}: {}", directory.display(), err))?;
+
+        let mut public_keys = HashMap::new();
+
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            let Some(kid) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".pub.pem")) else {
+                continue;
+            };
+
+            let pem = read_pem(&path)?;
+            let jwk = public_jwk(algorithm, kid, &pem)
+                .map_err(|err| format!("couldn't load {}: {}", path.display(), err))?;
+            public_keys.insert(kid.to_string(), jwk);
+        }
+
+        let signing_path = directory.join(format!("{signing_kid}.pem"));
+        let pem = read_pem(&signing_path)?;
+        let (encoding_key, jwk) = private_jwk(algorithm, signing_kid, &pem)
+            .map_err(|err| format!("couldn't load {}: {}", signing_path.display(), err))?;
+        // The public key is derived from the private one, a separate file isn't needed
+        public_keys.insert(signing_kid.to_string(), jwk);
+
+        let mut decoding_keys = HashMap::new();
+        for (kid, jwk) in &public_keys {
+            let decoding_key = DecodingKey::from_jwk(jwk)
+                .map_err(|err| format!("couldn't use the key {}: {}", kid, err))?;
+            decoding_keys.insert(Some(kid.clone()), decoding_key);
+        }
+
+        let mut keys: Vec<Jwk> = public_keys.into_values().collect();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+
+        Ok(JwtKeys {
+            algorithm,
+            kid: Some(signing_kid.to_string()),
+            encoding_key,
+            decoding_keys,
+            jwks: JwkSet { keys },
+        })
+    }
+
+    pub(crate) fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub(crate) fn kid(&self) -> Option<String> {
+        self.kid.clone()
+    }
+
+    pub(crate) fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub(crate) fn decoding_key(&self, kid: Option<String>) -> Option<&DecodingKey> {
+        self.decoding_keys.get(&kid)
+    }
+
+    pub(crate) fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+fn read_pem(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+}
+
+fn common_parameters(algorithm: Algorithm, kid: &str) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(match algorithm {
+            Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+            _ => KeyAlgorithm::RS256,
+        }),
+        key_id: Some(kid.to_string()),
+        ..Default::default()
+    }
+}
+
+fn rsa_jwk(kid: &str, key: &RsaPublicKey) -> Jwk {
+    Jwk {
+        common: common_parameters(Algorithm::RS256, kid),
+        algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        }),
+    }
+}
+
+fn ed25519_jwk(kid: &str, key: &VerifyingKey) -> Jwk {
+    Jwk {
+        common: common_parameters(Algorithm::EdDSA, kid),
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(key.as_bytes()),
+        }),
+    }
+}
+
+// Accepts PKCS#8 and, for RSA, PKCS#1 keys
+fn public_jwk(algorithm: Algorithm, kid: &str, pem: &str) -> Result<Jwk, String> {
+    match algorithm {
+        Algorithm::RS256 => {
+            let key = rsa::pkcs8::DecodePublicKey::from_public_key_pem(pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                .map_err(|err| err.to_string())?;
+            Ok(rsa_jwk(kid, &key))
+        }
+        Algorithm::EdDSA => {
+            let key: VerifyingKey = ed25519_dalek::pkcs8::DecodePublicKey::from_public_key_pem(pem)
+                .map_err(|err| err.to_string())?;
+            Ok(ed25519_jwk(kid, &key))
+        }
+        other => Err(format!("unsupported algorithm {other:?}")),
+    }
+}
+
+fn private_jwk(algorithm: Algorithm, kid: &str, pem: &str) -> Result<(EncodingKey, Jwk), String> {
+    match algorithm {
+        Algorithm::RS256 => {
+            let key = rsa::pkcs8::DecodePrivateKey::from_pkcs8_pem(pem)
+                .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+                .map_err(|err| err.to_string())?;
+            let encoding_key = EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|err| err.to_string())?;
+            Ok((encoding_key, rsa_jwk(kid, &key.to_public_key())))
+        }
+        Algorithm::EdDSA => {
+            let key: SigningKey = ed25519_dalek::pkcs8::DecodePrivateKey::from_pkcs8_pem(pem)
+                .map_err(|err| err.to_string())?;
+            let encoding_key = EncodingKey::from_ed_pem(pem.as_bytes()).map_err(|err| err.to_string())?;
+            Ok((encoding_key, ed25519_jwk(kid, &key.verifying_key())))
+        }
+        other => Err(format!("unsupported algorithm {other:?}")),
+    }
+}
+
+// Lets other services verify our tokens, lives at the root like the other well-known documents
+pub fn jwks_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(jwks_get);
+}
+
+#[utoipa::path(tag = "operations", responses((status = 200, description = "The public keys login tokens are signed with, as a JSON Web Key Set")))]
+#[get("/.well-known/jwks.json")]
+async fn jwks_get(jwt_keys: web::Data<JwtKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        // Short enough for verifiers to pick up a new key before it is used for signing
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(300)]))
+        .json(jwt_keys.jwks())
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use actix_web::{delete, get, HttpResponse, Scope, web};
+use chrono::{DateTime, Duration, Utc};
+use tracing::{error, warn};
+use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
+use crate::audit::{Auditor, snapshot};
+use crate::config::LockoutConfig;
+use crate::definitions::{Lockout, User};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+// Failures older than this are forgotten
+const FORGET_AFTER_HOURS: i64 = 24;
+
+// Failed logins from one address, only kept in memory
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct AddressLockout {
+    #[schema(value_type = String)]
+    address: IpAddr,
+    failures: i64,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Slows down password guessing, per address and per account
+///
+/// Both get a number of free attempts, after that every failure locks them out for twice as long as the one before.
+/// Lockouts of accounts are stored in the database, those of addresses only live as long as the server.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    database_manager: DatabaseManager,
+    config: LockoutConfig,
+    addresses: Arc<Mutex<HashMap<IpAddr, AddressLockout>>>,
+}
+
+impl LoginLimiter {
+    pub fn new(database_manager: DatabaseManager, config: LockoutConfig) -> Self {
+        Self {
+            database_manager,
+            config,
+            addresses: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Fails with [`ApiError::TooManyRequests`] while the address is locked out
+    pub fn check_address(&self, address: Option<IpAddr>) -> Result<(), ApiError> {
+        let Some(address) = address else { return Ok(()) };
+
+        let addresses = self.addresses.lock().unwrap();
+        check_locked_until(addresses.get(&address).and_then(|lockout| lockout.locked_until))
+    }
+
+    /// Fails with [`ApiError::TooManyRequests`] while the account is locked out
+    pub async fn check_account(&self, user: &User) -> Result<(), ApiError> {
+        let lockout = self.database_manager.fetch_lockout(user.id.to_string()).await?;
+        check_locked_until(lockout.and_then(|lockout| lockout.locked_until))
+    }
+
+    /// Counts a failed attempt against the address and, if it is known, the account
+    pub async fn record_failure(&self, address: Option<IpAddr>, user: Option<&User>) {
+        if let Some(address) = address {
+            self.record_address_failure(address);
+        }
+
+        if let Some(user) = user {
+            if let Err(err) = self.record_account_failure(user).await {
+                error!("Couldn't record the failed login of {}: {}", user.id, err);
+            }
+        }
+    }
+
+    /// Forgets the failed attempts of an account after it logged in
+    pub async fn record_success(&self, user: &User) -> Result<(), ApiError> {
+        self.database_manager.delete_lockout(user.id.to_string()).await?;
+        Ok(())
+    }
+
+    pub fn address_lockouts(&self) -> Vec<AddressLockout> {
+        self.addresses.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn clear_address(&self, address: &IpAddr) -> Option<AddressLockout> {
+        self.addresses.lock().unwrap().remove(address)
+    }
+
+    fn record_address_failure(&self, address: IpAddr) {
+        let now = Utc::now();
+        let mut addresses = self.addresses.lock().unwrap();
+
+        // Nothing else cleans up the map
+        addresses.retain(|_, lockout| lockout.last_failure > now - Duration::hours(FORGET_AFTER_HOURS));
+
+        let lockout = addresses.entry(address).or_insert(AddressLockout {
+            address,
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+        lockout.failures += 1;
+        lockout.last_failure = now;
+        lockout.locked_until = lock_duration(&self.config, lockout.failures, self.config.free_address_failures).map(|duration| lock_end(now, duration));
+
+        if lockout.locked_until.is_some() {
+            warn!("Locked out {} after {} failed logins", address, lockout.failures);
+        }
+    }
+
+    async fn record_account_failure(&self, user: &User) -> Result<(), ApiError> {
+        let failures = self.database_manager.add_login_failure(user.id.to_string(), Duration::hours(FORGET_AFTER_HOURS)).await?;
+
+        if let Some(duration) = lock_duration(&self.config, failures, self.config.free_account_failures) {
+            warn!("Locked out user {} after {} failed logins", user.id, failures);
+            self.database_manager.lock_user(user.id.to_string(), lock_end(Utc::now(), duration)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn lock_duration(config: &LockoutConfig, failures: i64, free_failures: i64) -> Option<Duration> {
+    if failures < free_failures {
+        return None;
+    }
+
+    // Capped before shifting, so the shift can't overflow, and saturated in case the base is large already
+    let doublings = (failures - free_failures).min(32) as u32;
+    let seconds = config.base_lock_seconds.saturating_mul(1 << doublings).min(config.max_lock_seconds);
+    // The configuration limits the maximum, this only keeps a bigger one from taking the login down
+    Some(Duration::try_seconds(seconds).unwrap_or(Duration::max_value()))
+}
+
+fn lock_end(now: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    now.checked_add_signed(duration).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+fn check_locked_until(locked_until: Option<DateTime<Utc>>) -> Result<(), ApiError> {
+    match locked_until {
+        Some(locked_until) if locked_until > Utc::now() => {
+            // Rounded up, retrying a second too early would fail again
+            let remaining = (locked_until - Utc::now()).num_milliseconds();
+            Err(ApiError::TooManyRequests(((remaining + 999) / 1000) as u64))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Lockouts {
+    accounts: Vec<Lockout>,
+    addresses: Vec<AddressLockout>,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(lockouts_get, account_lockout_delete, address_lockout_delete))]
+pub(crate) struct LockoutApi;
+
+pub fn lockout_service() -> Scope {
+    web::scope("/api/v1/lockouts")
+        .service(lockouts_get)
+        .service(account_lockout_delete)
+        .service(address_lockout_delete)
+}
+
+#[utoipa::path(tag = "lockouts", responses((status = 200, body = Lockouts)), security(("bearer" = [])))]
+#[get("")]
+async fn lockouts_get(
+    user: User,
+    login_limiter: web::Data<LoginLimiter>,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    Ok(HttpResponse::Ok().json(Lockouts {
+        accounts: database_manager.fetch_lockouts().await?,
+        addresses: login_limiter.address_lockouts(),
+    }))
+}
+
+#[utoipa::path(tag = "lockouts", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/accounts/{userId}")]
+async fn account_lockout_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let user_id = path.into_inner();
+
+    user.require_admin()?;
+
+    let lockout = database_manager.delete_lockout(user_id.clone()).await?
+        .ok_or(ApiError::NotFound)?;
+    auditor.record(&user, "lockout.clear", format!("user:{}", user_id), snapshot(&lockout), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(tag = "lockouts", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/addresses/{address}")]
+async fn address_lockout_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    login_limiter: web::Data<LoginLimiter>) -> Result<HttpResponse, ApiError> {
+
+    user.require_admin()?;
+
+    let address: IpAddr = path.parse()
+        .map_err(|_| ApiError::BadRequest("Not a valid ip address".to_string()))?;
+
+    let lockout = login_limiter.clear_address(&address)
+        .ok_or(ApiError::NotFound)?;
+    auditor.record(&user, "lockout.clear", format!("address:{}", address), snapshot(&lockout), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LockoutConfig {
+        LockoutConfig { base_lock_seconds: 30, max_lock_seconds: 60 * 60, ..LockoutConfig::default() }
+    }
+
+    #[test]
+    fn free_failures_dont_lock() {
+        assert_eq!(lock_duration(&config(), 0, 5), None);
+        assert_eq!(lock_duration(&config(), 4, 5), None);
+    }
+
+    #[test]
+    fn every_failure_doubles_the_lock() {
+        assert_eq!(lock_duration(&config(), 5, 5), Some(Duration::seconds(30)));
+        assert_eq!(lock_duration(&config(), 6, 5), Some(Duration::seconds(60)));
+        assert_eq!(lock_duration(&config(), 8, 5), Some(Duration::seconds(240)));
+    }
+
+    #[test]
+    fn lock_is_capped() {
+        assert_eq!(lock_duration(&config(), 12, 5), Some(Duration::seconds(60 * 60)));
+        // Far more failures than bits to shift
+        assert_eq!(lock_duration(&config(), 1000, 5), Some(Duration::seconds(60 * 60)));
+        assert_eq!(lock_duration(&config(), i64::MAX, 0), Some(Duration::seconds(60 * 60)));
+    }
+
+    #[test]
+    fn huge_max_doesnt_panic() {
+        let config = LockoutConfig { base_lock_seconds: i64::MAX / 4, max_lock_seconds: i64::MAX, ..LockoutConfig::default() };
+
+        let duration = lock_duration(&config, 1000, 5).unwrap();
+        assert_eq!(duration, Duration::max_value());
+        assert_eq!(lock_end(Utc::now(), duration), DateTime::<Utc>::MAX_UTC);
+    }
+
+    #[test]
+    fn large_base_doesnt_overflow() {
+        let config = LockoutConfig { base_lock_seconds: i64::MAX / 4, max_lock_seconds: 365 * 24 * 60 * 60, ..LockoutConfig::default() };
+        assert_eq!(lock_duration(&config, 100, 5), Some(Duration::seconds(365 * 24 * 60 * 60)));
+    }
+}
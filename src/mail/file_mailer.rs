@@ -0,0 +1,39 @@
+use std::error::Error;
+use std::path::PathBuf;
+use async_trait::async_trait;
+use chrono::Utc;
+use tracing::info;
+use tokio::fs;
+use uuid::Uuid;
+use crate::mail::mailer::{Mail, MailerTrait};
+
+// Doesn't send anything, meant for local development and tests.
+// Every mail is logged and, if a directory is given, written to a file in it.
+pub struct FileMailer {
+    directory: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        Self { directory }
+    }
+}
+
+#[async_trait]
+impl MailerTrait for FileMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let contents = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+
+        match &self.directory {
+            Some(directory) => {
+                fs::create_dir_all(directory).await?; // Create directories if not present
+                let path = directory.join(format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S"), Uuid::new_v4()));
+                fs::write(&path, contents).await?;
+                info!("Wrote mail to {} to {}", mail.to, path.display());
+            }
+            None => info!("Not sending mail:\n{}", contents),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// A plain text mail to a single recipient
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+}
+
+#[async_trait]
+pub trait MailerTrait: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    mailer: Arc<dyn MailerTrait>, // Dynamically dispatched mailer implementation
+}
+
+impl Mailer {
+    pub fn new(mailer: Arc<dyn MailerTrait>) -> Self {
+        Self { mailer }
+    }
+
+    /// Sends a mail, returns once the mail has been handed over to the transport
+    pub async fn send(&self, mail: &Mail) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.mailer.send(mail).await
+    }
+}
@@ -0,0 +1,43 @@
+use std::error::Error;
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::info;
+use crate::mail::mailer::{Mail, MailerTrait};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Connects to `host` using STARTTLS, credentials are optional for relays which don't need them
+    pub fn new(host: &str, port: u16, credentials: Option<(String, String)>, from: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?.port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        info!("Sending mails through {}:{} as {}", host, port, from);
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl MailerTrait for SmtpMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse()?)
+            .subject(&mail.subject)
+            .body(mail.body.clone())?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
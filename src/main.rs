@@ -1,17 +1,33 @@
-use std::env;
 use std::io::{Error, ErrorKind};
+use std::process;
 use std::sync::Arc;
-use actix_web::{App, HttpServer};
+use actix_web::{App, HttpResponse, HttpServer, web};
+use actix_web::middleware::{from_fn, Compress};
 use actix_web::web::Data;
 use dotenv::dotenv;
-use crate::storage::database_manager::{DatabaseManager};
+use crate::storage::database_manager::{ConnectionSettings, DatabaseManager};
 use crate::storage::s3_storage_manager::S3SystemStorage;
 use crate::storage::storage_manager::StorageManager;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use jsonwebtoken::Algorithm;
 use crate::auth::auth_service;
+use crate::config::{AuthConfig, MailConfig, MailTransport, OAuthSettings, StorageBackend};
+use crate::definitions::SiteInfo;
+use crate::cookie_auth::CookieAuth;
+use crate::error::ApiError;
+use crate::keys::JwtKeys;
+use crate::lockout::LoginLimiter;
+use crate::middleware::client_ip::TrustedProxies;
+use crate::mail::file_mailer::FileMailer;
+use crate::mail::mailer::{Mailer, MailerTrait};
+use crate::mail::smtp_mailer::SmtpMailer;
+use crate::oauth::{OAuthConfig, OAuthProvider, ProviderKind};
 
 mod api { // Declare the 'api' module
+    pub mod feed;
+    pub mod invites;
     pub mod post;
+    pub mod seo;
+    pub mod storage;
     pub mod users;
 }
 
@@ -19,61 +35,214 @@ mod storage { // Declare the 'storage' module
     pub mod storage_manager;
     pub mod s3_storage_manager;
     pub mod database_manager;
+    pub mod reconcile;
+    pub mod trash;
+}
+
+mod mail { // Declare the 'mail' module
+    pub mod mailer;
+    pub mod smtp_mailer;
+    pub mod file_mailer;
+}
+
+mod middleware { // Declare the 'middleware' module
+    pub mod client_ip;
+    pub mod cors;
+    pub mod database;
+    pub mod metrics;
+    pub mod request_id;
+    pub mod security_headers;
 }
 
 mod definitions;
+mod audit;
 mod auth;
+mod config;
+mod cookie_auth;
+mod error;
+mod health;
+mod keys;
+mod lockout;
+mod metrics;
+mod oauth;
+mod openapi;
+mod passwords;
+mod sessions;
+mod telemetry;
+mod tokens;
+mod totp;
 
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
     dotenv().ok();
-    // For debugging purposes, we will eventually remove this.
-    env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+
+    let tracer_provider = match telemetry::init(&config.server, &config.tracing) {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
 
     // Initiate storage
-    let file_path = get_env_var("FILE_PATH")?;
+    let storage_manager = match config.storage.backend {
+        StorageBackend::Filesystem => StorageManager::new(Arc::new(S3SystemStorage::new(config.storage.path.clone()))),
+    };
 
-    let storage_manager = StorageManager::new(Arc::new(S3SystemStorage::new(file_path)));
+    let jwt_keys = init_jwt_keys(&config.auth)?;
 
-    let db_host = get_env_var("DB_HOST")?;
-    let db_name = get_env_var("DB_USER")?;
-    let db_pass = get_env_var("DB_PASS")?;
-    let db_database = get_env_var("DB_DATABASE")?;
-    let db_namespace = get_env_var("DB_DATABASE")?;
+    let cookie_auth = config.auth.cookie
+        .then(|| CookieAuth::new(config.auth.cookie_domain.clone(), chrono::Duration::days(config.tokens.login_days)));
 
-    let jwt_secret = get_env_var("JWT_SECRET")?;
+    let site_info = SiteInfo {
+        url: config.site.url.trim_end_matches('/').to_string(),
+        title: config.site.title.clone(),
+        description: config.site.description.clone(),
+        image: config.site.image.clone(),
+    };
 
-    let db_manager;
-    match DatabaseManager::init(db_host, db_name, db_pass, db_database, db_namespace).await {
-        Ok(manager) => {
-            db_manager = manager;
-        },
-        Err(e) => {
-            panic!("couldn't initialize database: {e}");
-        }
-    }
+    let mailer = Mailer::new(init_mailer(&config.mail)?);
+    let oauth_config = init_oauth(&config.oauth, &config.server.api_url);
+
+    let registration_mode = config.auth.registration;
+    let token_lifetimes = config.tokens;
+    let limits = config.limits;
+    let cors_config = config.cors.clone();
+    let headers_config = config.headers.clone();
+    let trusted_proxies = TrustedProxies::new(&config.server.trusted_proxies);
 
-    HttpServer::new(move || {
-        let auth_manager = auth::AuthManager::new(Algorithm::HS256, EncodingKey::from_secret(jwt_secret.as_ref()), DecodingKey::from_secret(jwt_secret.as_ref()));
+    // The server starts right away, requests are answered with 503 until the database is connected
+    let db_manager = DatabaseManager::new(ConnectionSettings {
+        host: config.database.host.clone(),
+        user: config.database.user.clone(),
+        password: config.database.password.clone(),
+        namespace: config.database.namespace(),
+        database: config.database.database.clone(),
+    });
+    actix_web::rt::spawn(db_manager.clone().maintain_connection());
+
+    // Shared by all workers, so the attempts are counted across them
+    let login_limiter = LoginLimiter::new(db_manager.clone(), config.lockout);
+
+    actix_web::rt::spawn(storage::trash::purge_trash_periodically(db_manager.clone(), storage_manager.clone(), chrono::Duration::days(config.storage.trash_retention_days)));
+
+    let server = HttpServer::new(move || {
+        let auth_manager = auth::AuthManager::new(jwt_keys.clone(), cookie_auth.clone(), token_lifetimes);
 
         App::new()
+            .wrap(from_fn(middleware::database::require_database))
+            .wrap(from_fn(middleware::cors::cors))
+            .wrap(from_fn(middleware::security_headers::security_headers))
+            // gzip, brotli or zstd, whatever the client accepts
+            .wrap(Compress::default())
+            .wrap(from_fn(middleware::metrics::track_requests))
+            .wrap(from_fn(middleware::request_id::request_id))
+            // Answer malformed requests with the same JSON errors as the handlers
+            .app_data(web::JsonConfig::default()
+                .limit(limits.max_json_bytes())
+                .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
+            .app_data(web::PathConfig::default().error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()))
             .app_data(Data::new(auth_manager))
+            .app_data(Data::new(jwt_keys.clone()))
             .app_data(Data::new(storage_manager.clone()))
             .app_data(Data::new(db_manager.clone()))
+            .app_data(Data::new(site_info.clone()))
+            .app_data(Data::new(mailer.clone()))
+            .app_data(Data::new(registration_mode))
+            .app_data(Data::new(oauth_config.clone()))
+            .app_data(Data::new(login_limiter.clone()))
+            .app_data(Data::new(limits))
+            .app_data(Data::new(cors_config.clone()))
+            .app_data(Data::new(headers_config.clone()))
+            .app_data(Data::new(trusted_proxies.clone()))
 
+            .service(oauth::oauth_service())
+            .service(tokens::token_service())
+            .service(sessions::session_service())
             .service(auth_service())
+            .service(audit::audit_service())
+            .service(lockout::lockout_service())
+            .service(api::invites::invite_service())
             .service(api::users::user_service())
             .service(api::post::blog_service())
+            .service(api::storage::storage_service())
+            .configure(api::feed::feed_service)
+            .configure(api::seo::seo_service)
+            .configure(keys::jwks_service)
+            .configure(health::health_service)
+            .configure(metrics::metrics_service)
+            .configure(openapi::openapi_service)
+            .default_service(web::to(|| async { Err::<HttpResponse, ApiError>(ApiError::NotFound) }))
     })
-        .workers(2)
-        .bind("0.0.0.0:6969")?
+        .workers(config.server.workers)
+        .bind(&config.server.bind)?
         .run()
-        .await
+        .await;
+
+    // Sends the spans which are still buffered
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("couldn't export the remaining spans: {e}");
+        }
+    }
+
+    server
 }
 
-fn get_env_var(key: &str) -> Result<String, Error> {
-    env::var(key).map_err(|e| {
-        Error::new(ErrorKind::InvalidInput, format!("couldn't interpret {key}: {e}"))
-    })
+fn init_jwt_keys(auth: &AuthConfig) -> Result<JwtKeys, Error> {
+    match auth.algorithm {
+        Algorithm::HS256 => Ok(JwtKeys::from_secret(auth.secret.clone().unwrap_or_default().as_bytes())),
+        algorithm => {
+            let signing_key = auth.signing_key.clone().unwrap_or_default();
+
+            JwtKeys::from_directory(algorithm, &auth.key_dir, &signing_key).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("couldn't load the jwt keys: {e}"))
+            })
+        }
+    }
+}
+
+fn init_mailer(mail: &MailConfig) -> Result<Arc<dyn MailerTrait>, Error> {
+    match mail.transport {
+        MailTransport::Smtp => {
+            let host = mail.smtp_host.clone().unwrap_or_default();
+            let credentials = mail.smtp_user.clone().zip(mail.smtp_password.clone());
+            let from = mail.from.clone().unwrap_or_default();
+
+            let mailer = SmtpMailer::new(&host, mail.smtp_port, credentials, &from).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("couldn't set up the smtp mailer: {e}"))
+            })?;
+            Ok(Arc::new(mailer))
+        }
+        MailTransport::File => Ok(Arc::new(FileMailer::new(mail.dir.clone()))),
+    }
+}
+
+fn init_oauth(oauth: &OAuthSettings, api_url: &str) -> OAuthConfig {
+    let mut providers = Vec::new();
+
+    for (kind, settings) in [(ProviderKind::GitHub, &oauth.github), (ProviderKind::Discord, &oauth.discord)] {
+        let Some(settings) = settings else { continue };
+
+        let (authorize_url, token_url, provider_api_url) = kind.default_urls();
+        providers.push(OAuthProvider {
+            kind,
+            client_id: settings.client_id.clone(),
+            client_secret: settings.client_secret.clone(),
+            authorize_url: settings.authorize_url.clone().unwrap_or_else(|| authorize_url.to_string()),
+            token_url: settings.token_url.clone().unwrap_or_else(|| token_url.to_string()),
+            api_url: settings.api_url.as_deref().unwrap_or(provider_api_url).trim_end_matches('/').to_string(),
+        });
+    }
+
+    OAuthConfig::new(providers, api_url.trim_end_matches('/').to_string(), oauth.accounts)
 }
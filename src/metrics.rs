@@ -0,0 +1,71 @@
+use std::sync::LazyLock;
+use actix_web::{get, HttpResponse, web};
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use crate::error::ApiError;
+
+// Every metric of the api, registered in our own registry so nothing else ends up in /metrics
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("http_requests_total", "Handled requests by method, route and status"),
+    &["method", "route", "status"],
+)));
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("http_request_duration_seconds", "Time taken to answer requests by method and route"),
+    &["method", "route"],
+)));
+
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("db_query_duration_seconds", "Time taken by the database operations of the DatabaseManager")
+        .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+    &["operation"],
+)));
+
+static STORAGE_OPERATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("storage_operations_total", "File storage operations by operation and outcome"),
+    &["operation", "outcome"],
+)));
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: Result<M, prometheus::Error>) -> M {
+    // The definitions above are fixed, so this can only fail on a programming error
+    let metric = metric.expect("invalid metric definition");
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+    metric
+}
+
+/// Counts a request and records how long it took
+///
+/// `route` has to be the pattern the request matched, not its path, or every id would become its own series.
+pub fn observe_request(method: &str, route: &str, status: u16, seconds: f64) {
+    HTTP_REQUESTS.with_label_values(&[method, route, status.to_string().as_str()]).inc();
+    HTTP_REQUEST_DURATION.with_label_values(&[method, route]).observe(seconds);
+}
+
+/// Measures a database operation until the returned timer is dropped
+pub fn time_query(operation: &str) -> HistogramTimer {
+    DB_QUERY_DURATION.with_label_values(&[operation]).start_timer()
+}
+
+pub fn count_storage_operation<T, E>(operation: &str, result: &Result<T, E>) {
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    STORAGE_OPERATIONS.with_label_values(&[operation, outcome]).inc();
+}
+
+pub fn metrics_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics_get);
+}
+
+// Prometheus text format, meant to be scraped from inside the cluster
+#[utoipa::path(tag = "operations", responses((status = 200, body = String, content_type = "text/plain", description = "Metrics in the Prometheus text format")))]
+#[get("/metrics")]
+async fn metrics_get() -> Result<HttpResponse, ApiError> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| ApiError::Internal(format!("couldn't encode the metrics: {e}")))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+use actix_web::http::header::HeaderName;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The reverse proxies in front of the server, whose `X-Forwarded-For` header is believed
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    // Entries which aren't addresses or networks are left out, the configuration is validated before
+    pub fn new(proxies: &[String]) -> Self {
+        TrustedProxies {
+            networks: proxies.iter().filter_map(|proxy| parse_network(proxy)).collect(),
+        }
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&address))
+    }
+}
+
+/// Parses a single address like "10.0.0.1" or a network like "10.0.0.0/8"
+pub fn parse_network(network: &str) -> Option<IpNet> {
+    network.parse::<IpNet>().ok()
+        .or_else(|| network.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// The address of the client which sent the request
+///
+/// Requests from a trusted proxy are followed back through `X-Forwarded-For`, every proxy appends the address it got
+/// the request from. The last entry which wasn't added by a trusted proxy is the client, everything before it could
+/// have been sent by the client itself. Without trusted proxies the header is ignored.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let Some(proxies) = req.app_data::<Data<TrustedProxies>>() else { return Some(peer) };
+
+    let forwarded: Vec<&str> = req.headers().get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    let mut client = peer;
+    for entry in forwarded.into_iter().rev() {
+        if !proxies.contains(client) {
+            break;
+        }
+        match entry.trim().parse() {
+            Ok(address) => client = address,
+            // Whatever comes before a broken entry can't be trusted either
+            Err(_) => break,
+        }
+    }
+
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn request(peer: &str, forwarded: Option<&str>, proxies: Option<&[&str]>) -> HttpRequest {
+        let mut req = TestRequest::default().peer_addr(format!("{peer}:4000").parse().unwrap());
+        if let Some(forwarded) = forwarded {
+            req = req.insert_header((X_FORWARDED_FOR, forwarded));
+        }
+        if let Some(proxies) = proxies {
+            let proxies: Vec<String> = proxies.iter().map(|proxy| proxy.to_string()).collect();
+            req = req.app_data(Data::new(TrustedProxies::new(&proxies)));
+        }
+        req.to_http_request()
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn header_is_ignored_without_trusted_proxies() {
+        let req = request("203.0.113.7", Some("198.51.100.1"), None);
+        assert_eq!(client_ip(&req), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn header_of_untrusted_peer_is_ignored() {
+        let req = request("203.0.113.7", Some("198.51.100.1"), Some(&["10.0.0.0/8"]));
+        assert_eq!(client_ip(&req), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn client_behind_trusted_proxy() {
+        let req = request("10.0.0.2", Some("198.51.100.1"), Some(&["10.0.0.0/8"]));
+        assert_eq!(client_ip(&req), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn spoofed_prefix_is_skipped() {
+        // The client sent "1.2.3.4" itself, the proxy appended the address it really came from
+        let req = request("10.0.0.2", Some("1.2.3.4, 198.51.100.1"), Some(&["10.0.0.0/8"]));
+        assert_eq!(client_ip(&req), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn chain_of_trusted_proxies_is_followed() {
+        let req = request("10.0.0.2", Some("1.2.3.4, 198.51.100.1, 10.0.0.3"), Some(&["10.0.0.0/8"]));
+        assert_eq!(client_ip(&req), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn malformed_entry_stops_at_last_trusted_hop() {
+        let req = request("10.0.0.2", Some("198.51.100.1, not-an-address"), Some(&["10.0.0.0/8"]));
+        assert_eq!(client_ip(&req), ip("10.0.0.2"));
+    }
+}
@@ -0,0 +1,72 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpResponse};
+use crate::config::CorsConfig;
+use crate::error::add_error_headers;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-CSRF-Token, X-Request-Id";
+// Headers the frontend may read from our responses
+const EXPOSED_HEADERS: &str = "X-Request-Id, Retry-After";
+
+/// Lets the frontends in `cors.allowed_origins` call the api from a browser
+///
+/// Preflight requests of allowed origins are answered right away. Requests from other origins are handled
+/// as usual but don't get any CORS headers, so browsers don't hand the response to the calling site.
+pub async fn cors(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let origin = req.headers().get(header::ORIGIN)
+        .filter(|origin| is_allowed(&req, origin))
+        .cloned();
+
+    let Some(origin) = origin else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let is_preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if is_preflight {
+        let max_age = req.app_data::<Data<CorsConfig>>().map(|config| config.max_age_seconds).unwrap_or_default();
+
+        let mut response = HttpResponse::NoContent()
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS))
+            .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, max_age.to_string()))
+            .finish();
+        add_origin_headers(response.headers_mut(), origin);
+
+        return Ok(req.into_response(response));
+    }
+
+    let add_headers = |headers: &mut HeaderMap| {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+        add_origin_headers(headers, origin);
+    };
+
+    // Errors need the headers as well, otherwise the frontend can't read why its request failed
+    match next.call(req).await {
+        Ok(response) => {
+            let mut response = response.map_into_boxed_body();
+            add_headers(response.headers_mut());
+            Ok(response)
+        }
+        Err(err) => Err(add_error_headers(err, add_headers)),
+    }
+}
+
+fn is_allowed(req: &ServiceRequest, origin: &HeaderValue) -> bool {
+    let Some(config) = req.app_data::<Data<CorsConfig>>() else { return false };
+    let Ok(origin) = origin.to_str() else { return false };
+
+    config.allowed_origins.iter().any(|allowed| allowed == origin)
+}
+
+fn add_origin_headers(headers: &mut HeaderMap, origin: HeaderValue) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    // Needed for the login cookies
+    headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    // The answer depends on the origin, caches must not hand it to other ones
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
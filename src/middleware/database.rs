@@ -0,0 +1,43 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::error::InternalError;
+use actix_web::{Error, ResponseError};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+// The connection is checked every five seconds, so that is when the client can expect a change
+const RETRY_AFTER_SECONDS: u64 = 5;
+// Paths which are answered without the database
+const WITHOUT_DATABASE: [&str; 6] = ["/.well-known/", "/healthz", "/readyz", "/metrics", "/api/openapi.json", "/api/docs"];
+
+/// Answers with 503 while the database is unavailable
+///
+/// Requests which are already running when the connection drops are cut short as well,
+/// otherwise they would wait for the database until it comes back.
+pub async fn require_database(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let database_manager = req.app_data::<Data<DatabaseManager>>().cloned();
+
+    let Some(database_manager) = database_manager.filter(|_| !WITHOUT_DATABASE.iter().any(|path| req.path().starts_with(path))) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let unavailable = ApiError::DatabaseUnavailable(RETRY_AFTER_SECONDS);
+    if !database_manager.is_available() {
+        return Ok(req.into_response(unavailable.error_response()));
+    }
+
+    let mut availability = database_manager.availability();
+
+    tokio::select! {
+        response = next.call(req) => Ok(response?.map_into_boxed_body()),
+        // The request went into the cancelled call, so answer through the error instead.
+        // Keeping a clone of it around isn't possible, routing needs the only reference.
+        // The response is rendered right away, later the request id wouldn't be known anymore.
+        _ = availability.wait_for(|available| !available) => {
+            let response = unavailable.error_response();
+            Err(InternalError::from_response(unavailable, response).into())
+        }
+    }
+}
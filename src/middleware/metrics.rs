@@ -0,0 +1,27 @@
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use crate::metrics::observe_request;
+
+// Label of requests which didn't match any route, so scanners can't create new series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Records every request in the `http_requests_total` and `http_request_duration_seconds` metrics
+pub async fn track_requests(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    // Known before routing, the request can't be kept around for afterwards as routing needs the only reference to it
+    let route = req.match_pattern().unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let method = req.method().to_string();
+
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    observe_request(&method, &route, status.as_u16(), started.elapsed().as_secs_f64());
+
+    result
+}
@@ -0,0 +1,79 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use std::time::Instant;
+use tracing::{field, info, info_span, Instrument};
+use uuid::Uuid;
+use crate::error::add_error_headers;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Gives every request an id which is sent back in the `X-Request-Id` header
+///
+/// An id sent by the client, e.g. by a reverse proxy, is reused so requests can be followed across services.
+/// The id is available through [`current_request_id`] while the request is handled. Everything logged meanwhile
+/// happens in a `request` span which carries the id, and a line with the outcome is logged once it is answered.
+pub async fn request_id(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let started = Instant::now();
+    let route = req.match_pattern();
+    let span = info_span!("request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        status = field::Empty,
+        // Names the exported span after the route, the path would make every post its own operation
+        otel.name = format!("{} {}", req.method(), route.as_deref().unwrap_or("unmatched")),
+        otel.kind = "server",
+    );
+
+    let result = REQUEST_ID.scope(request_id.clone(), next.call(req))
+        .instrument(span.clone())
+        .await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    span.record("status", status.as_u16());
+    span.in_scope(|| info!(status = status.as_u16(), duration_ms = started.elapsed().as_millis() as u64, "Answered request"));
+
+    let header = HeaderValue::from_str(&request_id).ok();
+    let add_header = |headers: &mut HeaderMap| {
+        if let Some(header) = header {
+            headers.insert(REQUEST_ID_HEADER, header);
+        }
+    };
+
+    match result {
+        Ok(mut response) => {
+            add_header(response.headers_mut());
+            Ok(response)
+        }
+        Err(err) => Err(add_error_headers(err, add_header)),
+    }
+}
+
+/// The id of the request currently being handled, if called from within a request
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+// We don't want clients to be able to put arbitrary data in our logs and responses
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
@@ -0,0 +1,54 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+use crate::config::HeadersConfig;
+use crate::error::add_error_headers;
+use crate::openapi::DOCS_PATH;
+
+// The api docs are a page which loads its script and fonts from cdns, the configured policy is meant for the api
+const DOCS_POLICY: &str = "default-src 'none'; script-src https://cdn.jsdelivr.net; \
+    style-src 'unsafe-inline' https://cdn.jsdelivr.net; font-src https://fonts.scalar.com; img-src 'self' data: https:; \
+    connect-src 'self'; frame-ancestors 'none'";
+
+/// Adds the headers which tell browsers to be careful with our responses
+///
+/// `X-Content-Type-Options` and `Referrer-Policy` are always sent, the `Content-Security-Policy` and
+/// `Strict-Transport-Security` come from `headers` in the configuration and are left out when they are turned off.
+pub async fn security_headers(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req.app_data::<Data<HeadersConfig>>().cloned();
+    let is_docs = req.path() == DOCS_PATH;
+
+    // Errors get them too, e.g. the 503 of a request which was cut short because the database is gone
+    let add_headers = |headers: &mut HeaderMap| {
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+
+        let Some(config) = config else { return };
+
+        let policy = if is_docs { DOCS_POLICY } else { &config.content_security_policy };
+        if !policy.is_empty() {
+            if let Ok(policy) = HeaderValue::from_str(policy) {
+                headers.insert(header::CONTENT_SECURITY_POLICY, policy);
+            }
+        }
+
+        // Browsers only keep it from https responses, so it does no harm in local development
+        if config.hsts_max_age_seconds > 0 {
+            let max_age = format!("max-age={}", config.hsts_max_age_seconds);
+            if let Ok(max_age) = HeaderValue::from_str(&max_age) {
+                headers.insert(header::STRICT_TRANSPORT_SECURITY, max_age);
+            }
+        }
+    };
+
+    match next.call(req).await {
+        Ok(mut response) => {
+            add_headers(response.headers_mut());
+            Ok(response)
+        }
+        Err(err) => Err(add_error_headers(err, add_headers)),
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use actix_web::{delete, FromRequest, get, HttpRequest, HttpResponse, post, Scope, web};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::http::header::LOCATION;
+use actix_web::web::Data;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use tracing::warn;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use crate::audit::{Auditor, snapshot};
+use crate::auth::{complete_login, issue_login, now, AuthManager, LoginResponse};
+use crate::definitions::{Identity, SiteInfo, User};
+use crate::error::ApiError;
+use crate::passwords::hash_password;
+use crate::storage::database_manager::DatabaseManager;
+
+const STATE_COOKIE: &str = "oauth_state";
+const STATE_AUDIENCE: &str = "oauth_state";
+// How long someone has to log in at the provider, in seconds
+const STATE_LIFETIME: usize = 10 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProviderKind {
+    GitHub,
+    Discord,
+}
+
+impl ProviderKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderKind::GitHub => "github",
+            ProviderKind::Discord => "discord",
+        }
+    }
+
+    // Authorize, token and api url of the real provider
+    pub fn default_urls(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ProviderKind::GitHub => ("https://github.com/login/oauth/authorize", "https://github.com/login/oauth/access_token", "https://api.github.com"),
+            ProviderKind::Discord => ("https://discord.com/oauth2/authorize", "https://discord.com/api/oauth2/token", "https://discord.com/api"),
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            ProviderKind::GitHub => "read:user user:email",
+            ProviderKind::Discord => "identify email",
+        }
+    }
+}
+
+/// A configured login provider, the urls can point somewhere else than the real provider e.g. a mock server
+#[derive(Clone, Debug)]
+pub struct OAuthProvider {
+    pub(crate) kind: ProviderKind,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) authorize_url: String,
+    pub(crate) token_url: String,
+    pub(crate) api_url: String,
+}
+
+/// What happens when someone logs in with an external account which isn't linked to a user yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OAuthAccountPolicy {
+    // Only accounts linked by their user beforehand can log in
+    Existing,
+    // Also link to the user with the same email, if both the provider and the user verified it
+    Email,
+    // Like Email, but create a new user if nobody has the email
+    Create,
+}
+
+impl FromStr for OAuthAccountPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "existing" => Ok(OAuthAccountPolicy::Existing),
+            "email" => Ok(OAuthAccountPolicy::Email),
+            "create" => Ok(OAuthAccountPolicy::Create),
+            _ => Err(format!("unknown account policy {policy}, expected existing, email or create")),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OAuthConfig {
+    providers: BTreeMap<&'static str, OAuthProvider>,
+    // Public url of this api, the callbacks are below it
+    api_url: String,
+    account_policy: OAuthAccountPolicy,
+    client: reqwest::Client,
+}
+
+impl OAuthConfig {
+    pub fn new(providers: Vec<OAuthProvider>, api_url: String, account_policy: OAuthAccountPolicy) -> Self {
+        Self {
+            providers: providers.into_iter().map(|provider| (provider.kind.name(), provider)).collect(),
+            api_url,
+            account_policy,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn provider(&self, name: &str) -> Result<&OAuthProvider, ApiError> {
+        self.providers.get(name).ok_or(ApiError::NotFound)
+    }
+
+    fn redirect_uri(&self, provider: &OAuthProvider) -> String {
+        format!("{}/api/v1/auth/oauth/{}/callback", self.api_url, provider.kind.name())
+    }
+}
+
+// Kept in a cookie between starting the login and the callback.
+// The verifier never leaves the server and the browser, the provider only sees the challenge derived from it.
+#[derive(Serialize, Deserialize)]
+struct OAuthState {
+    exp: usize,
+    aud: String,
+    provider: String,
+    state: String,
+    verifier: String,
+    // Set if a logged in user links an account instead of logging in
+    link: Option<String>,
+}
+
+// The parts of the account at the provider we care about
+struct ExternalAccount {
+    subject: String,
+    username: String,
+    // Only set if the provider verified the address
+    email: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: i64,
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+#[derive(Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: String,
+    email: Option<String>,
+    verified: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuthorizeUrl {
+    url: String,
+}
+
+enum CallbackResult {
+    Login(LoginResponse),
+    Linked,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(identities_get, identity_delete, oauth_start, oauth_link, oauth_callback))]
+pub(crate) struct OAuthApi;
+
+// Registered before the auth service, otherwise its scope would swallow these routes
+pub fn oauth_service() -> Scope {
+    web::scope("/api/v1/auth/oauth")
+        .service(identities_get)
+        .service(identity_delete)
+        .service(oauth_start)
+        .service(oauth_link)
+        .service(oauth_callback)
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200, body = Vec<Identity>)), security(("bearer" = [])))]
+#[get("/identities")]
+async fn identities_get(
+    user: User,
+    database_manager: Data<DatabaseManager>,
+) -> Result<HttpResponse, ApiError> {
+    let identities = database_manager.fetch_identities(user.id.to_string()).await?;
+    Ok(HttpResponse::Ok().json(identities))
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/identities/{provider}")]
+async fn identity_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: Data<DatabaseManager>,
+) -> Result<HttpResponse, ApiError> {
+    let provider = path.into_inner();
+
+    let removed = database_manager.unlink_identity(user.id.to_string(), provider.clone()).await?;
+    if removed.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+
+    auditor.record(&user, "user.identity.unlink", format!("user:{}", user.id), Some(json!({ "provider": provider })), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Sends the browser to the provider
+#[utoipa::path(tag = "auth", responses((status = 302, description = "Redirects to the provider")))]
+#[get("/{provider}")]
+async fn oauth_start(
+    path: web::Path<String>,
+    oauth_config: Data<OAuthConfig>,
+    auth_manager: Data<AuthManager>,
+) -> Result<HttpResponse, ApiError> {
+    let provider = oauth_config.provider(&path)?;
+    let (url, cookie) = authorize(&oauth_config, provider, &auth_manager, None)?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, url))
+        .cookie(cookie)
+        .finish())
+}
+
+// Same as oauth_start, but for a logged in user who wants to link an account.
+// The frontend has to send the browser to the returned url itself, a redirect can't carry the token.
+#[utoipa::path(tag = "auth", responses((status = 200, body = AuthorizeUrl)), security(("bearer" = [])))]
+#[post("/{provider}/link")]
+async fn oauth_link(
+    user: User,
+    path: web::Path<String>,
+    oauth_config: Data<OAuthConfig>,
+    auth_manager: Data<AuthManager>,
+) -> Result<HttpResponse, ApiError> {
+    let provider = oauth_config.provider(&path)?;
+    let (url, cookie) = authorize(&oauth_config, provider, &auth_manager, Some(user.id.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(AuthorizeUrl { url }))
+}
+
+// The provider sends the browser back here. The result is passed on to the frontend in the fragment of the url,
+// which never reaches a server.
+#[utoipa::path(tag = "auth", params(CallbackQuery), responses((status = 302, description = "Redirects to the frontend, the outcome is in the fragment")))]
+#[get("/{provider}/callback")]
+async fn oauth_callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+    oauth_config: Data<OAuthConfig>,
+    auth_manager: Data<AuthManager>,
+    database_manager: Data<DatabaseManager>,
+    site_info: Data<SiteInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let result = finish_callback(&req, &path, query.into_inner(), &oauth_config, &auth_manager, &database_manager).await;
+
+    let mut response = HttpResponse::Found();
+
+    let fragment = match result {
+        Ok(CallbackResult::Login(login)) => match issue_login(&auth_manager, login, &mut response) {
+            LoginResponse::Token { token } => format!("token={}", token),
+            LoginResponse::Challenge { challenge } => format!("challenge={}", challenge),
+            LoginResponse::Cookie { csrf_token } => format!("csrf_token={}", csrf_token),
+        },
+        Ok(CallbackResult::Linked) => format!("linked={}", path),
+        Err(err) => {
+            warn!("OAuth login with {} failed: {}", path, err);
+            format!("error={}", err.code())
+        }
+    };
+
+    let mut removal = Cookie::build(STATE_COOKIE, "").path("/api/v1/auth/oauth").finish();
+    removal.make_removal();
+
+    Ok(response
+        .insert_header((LOCATION, format!("{}/login/oauth#{}", site_info.url, fragment)))
+        .cookie(removal)
+        .finish())
+}
+
+fn authorize(oauth_config: &OAuthConfig, provider: &OAuthProvider, auth_manager: &AuthManager, link: Option<String>) -> Result<(String, Cookie<'static>), ApiError> {
+    let state = random_string(32);
+    let verifier = random_string(64);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    let signed_state = auth_manager.create_signed(&OAuthState {
+        exp: now() + STATE_LIFETIME,
+        aud: STATE_AUDIENCE.to_string(),
+        provider: provider.kind.name().to_string(),
+        state: state.clone(),
+        verifier,
+        link,
+    }).map_err(|err| ApiError::Internal(format!("failed to create token: {err}")))?;
+
+    let url = reqwest::Url::parse_with_params(&provider.authorize_url, &[
+        ("response_type", "code"),
+        ("client_id", provider.client_id.as_str()),
+        ("redirect_uri", oauth_config.redirect_uri(provider).as_str()),
+        ("scope", provider.kind.scope()),
+        ("state", state.as_str()),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ]).map_err(|err| ApiError::Internal(format!("invalid authorize url: {err}")))?;
+
+    // Lax, the callback is a top level navigation coming from the provider
+    let cookie = Cookie::build(STATE_COOKIE, signed_state)
+        .path("/api/v1/auth/oauth")
+        .http_only(true)
+        .secure(oauth_config.api_url.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(STATE_LIFETIME as i64))
+        .finish();
+
+    Ok((url.to_string(), cookie))
+}
+
+async fn finish_callback(
+    req: &HttpRequest,
+    provider_name: &str,
+    query: CallbackQuery,
+    oauth_config: &OAuthConfig,
+    auth_manager: &AuthManager,
+    database_manager: &DatabaseManager,
+) -> Result<CallbackResult, ApiError> {
+    let provider = oauth_config.provider(provider_name)?;
+
+    if let Some(error) = query.error {
+        return Err(ApiError::Provider(format!("authorization failed: {error}")));
+    }
+
+    let cookie = req.cookie(STATE_COOKIE).ok_or(ApiError::InvalidToken)?;
+    let state: OAuthState = auth_manager.validate_signed(cookie.value(), STATE_AUDIENCE)?;
+
+    if state.provider != provider_name || query.state.as_deref() != Some(state.state.as_str()) {
+        return Err(ApiError::InvalidToken);
+    }
+
+    let code = query.code.ok_or(ApiError::BadRequest("The provider didn't send a code".to_string()))?;
+    let access_token = exchange_code(oauth_config, provider, &code, &state.verifier).await?;
+    let account = fetch_account(oauth_config, provider, &access_token).await?;
+    // Links and new users end up in the audit log, they decide who can log in as whom
+    let auditor = Auditor::extract(req).await?;
+
+    if let Some(user_id) = state.link {
+        let user = database_manager.fetch_user(user_id).await?
+            .ok_or(ApiError::UserNotFound)?;
+
+        link(&auditor, database_manager, provider, &account, &user).await?;
+        return Ok(CallbackResult::Linked);
+    }
+
+    let user = match database_manager.fetch_identity_user(provider_name.to_string(), account.subject.clone()).await? {
+        Some(user) => user,
+        None => link_or_create(&auditor, oauth_config, database_manager, provider, &account).await?,
+    };
+
+    Ok(CallbackResult::Login(complete_login(auth_manager, database_manager, req, &user).await?))
+}
+
+// Applies the account policy to an account which isn't linked yet
+async fn link_or_create(auditor: &Auditor, oauth_config: &OAuthConfig, database_manager: &DatabaseManager, provider: &OAuthProvider, account: &ExternalAccount) -> Result<User, ApiError> {
+    if oauth_config.account_policy == OAuthAccountPolicy::Existing {
+        return Err(ApiError::AccountNotLinked);
+    }
+
+    let email = account.email.clone().ok_or(ApiError::AccountNotLinked)?;
+
+    let existing = database_manager.fetch_user(email.clone()).await?
+        .filter(|user| user.email == email);
+
+    let user = match existing {
+        Some(user) if can_link_by_email(&user, &email) => user,
+        // Has to be linked by the user themselves after logging in
+        Some(_) => return Err(ApiError::AccountNotLinked),
+        None if oauth_config.account_policy == OAuthAccountPolicy::Create => {
+            // Fall back to a name with the provider in it, if that is taken too an admin has to sort it out
+            let mut name = account.username.replace('@', "");
+            if database_manager.is_user_taken(name.clone(), email.clone(), None).await? {
+                name = format!("{}-{}", name, provider.kind.name());
+            }
+            if database_manager.is_user_taken(name.clone(), email.clone(), None).await? {
+                return Err(ApiError::Conflict("The name or email is already taken".to_string()));
+            }
+
+            // Nobody knows this password, it can be set through a password reset
+            let password = hash_password(random_string(64)).await?;
+            let user = database_manager.register_user(name, email, true, password, None).await?
+                .ok_or(ApiError::Internal("the user wasn't created".to_string()))?;
+            auditor.record(&user, "user.register", format!("user:{}", user.id), Some(json!({ "provider": provider.kind.name() })), snapshot(&user)).await;
+            user
+        }
+        None => return Err(ApiError::AccountNotLinked),
+    };
+
+    link(auditor, database_manager, provider, account, &user).await?;
+    Ok(user)
+}
+
+// Anyone can register with an address they don't own, linking to such a user would hand the account at the provider
+// to whoever registered it. Both sides have to have verified the address, the provider side is checked in fetch_account.
+fn can_link_by_email(user: &User, email: &str) -> bool {
+    user.email_verified && user.email == email
+}
+
+async fn link(auditor: &Auditor, database_manager: &DatabaseManager, provider: &OAuthProvider, account: &ExternalAccount, user: &User) -> Result<(), ApiError> {
+    let linked_user = database_manager.fetch_identity_user(provider.kind.name().to_string(), account.subject.clone()).await?;
+
+    match linked_user {
+        Some(linked_user) if linked_user.id == user.id => Ok(()),
+        Some(_) => Err(ApiError::Conflict("The account is already linked to another user".to_string())),
+        None => {
+            database_manager.link_identity(provider.kind.name().to_string(), account.subject.clone(), account.username.clone(), user.id.to_string()).await?;
+            auditor.record(user, "user.identity.link", format!("user:{}", user.id), None,
+                Some(json!({ "provider": provider.kind.name(), "username": account.username }))).await;
+            Ok(())
+        }
+    }
+}
+
+async fn exchange_code(oauth_config: &OAuthConfig, provider: &OAuthProvider, code: &str, verifier: &str) -> Result<String, ApiError> {
+    let response = oauth_config.client
+        .post(&provider.token_url)
+        .header(ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", oauth_config.redirect_uri(provider).as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("code_verifier", verifier),
+        ])
+        .send()
+        .await
+        .map_err(|err| ApiError::Provider(format!("token request failed: {err}")))?;
+
+    let token: TokenResponse = parse_response(response).await?;
+    Ok(token.access_token)
+}
+
+async fn fetch_account(oauth_config: &OAuthConfig, provider: &OAuthProvider, access_token: &str) -> Result<ExternalAccount, ApiError> {
+    match provider.kind {
+        ProviderKind::GitHub => {
+            let user: GitHubUser = api_get(oauth_config, provider, "/user", access_token).await?;
+            let emails: Vec<GitHubEmail> = api_get(oauth_config, provider, "/user/emails", access_token).await?;
+
+            Ok(ExternalAccount {
+                subject: user.id.to_string(),
+                username: user.login,
+                email: github_email(emails),
+            })
+        }
+        ProviderKind::Discord => {
+            let user: DiscordUser = api_get(oauth_config, provider, "/users/@me", access_token).await?;
+
+            Ok(ExternalAccount {
+                subject: user.id,
+                username: user.username,
+                email: discord_email(user.email, user.verified),
+            })
+        }
+    }
+}
+
+// The primary address, if GitHub verified it
+fn github_email(emails: Vec<GitHubEmail>) -> Option<String> {
+    emails.into_iter()
+        .find(|email| email.primary && email.verified)
+        .map(|email| email.email)
+}
+
+// Discord leaves out `verified` without the email scope, that counts as unverified
+fn discord_email(email: Option<String>, verified: Option<bool>) -> Option<String> {
+    email.filter(|_| verified.unwrap_or(false))
+}
+
+async fn api_get<T: DeserializeOwned>(oauth_config: &OAuthConfig, provider: &OAuthProvider, path: &str, access_token: &str) -> Result<T, ApiError> {
+    let response = oauth_config.client
+        .get(format!("{}{}", provider.api_url, path))
+        .bearer_auth(access_token)
+        .header(ACCEPT, "application/json")
+        // GitHub rejects requests without one
+        .header(USER_AGENT, "intelligence-homepage")
+        .send()
+        .await
+        .map_err(|err| ApiError::Provider(format!("request to {path} failed: {err}")))?;
+
+    parse_response(response).await
+}
+
+async fn parse_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, ApiError> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ApiError::Provider(format!("{} answered with {}", response.url(), status)));
+    }
+
+    response.json().await
+        .map_err(|err| ApiError::Provider(format!("unexpected response: {err}")))
+}
+
+fn random_string(length: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Id;
+    use crate::definitions::{IntelliThing, ProfileVisibility};
+    use super::*;
+
+    fn user(email: &str, email_verified: bool) -> User {
+        User {
+            id: IntelliThing { id: Id::from("victim") },
+            admin: false,
+            name: "victim".to_string(),
+            email: email.to_string(),
+            email_verified,
+            password: String::new(),
+            password_changed: None,
+            two_factor: false,
+            totp_secret: None,
+            totp_pending: None,
+            firstname: None,
+            lastname: None,
+            display_name: None,
+            bio: None,
+            links: BTreeMap::new(),
+            visibility: ProfileVisibility::default(),
+            deleted_at: None,
+            session: None,
+        }
+    }
+
+    fn github(email: &str, primary: bool, verified: bool) -> GitHubEmail {
+        GitHubEmail { email: email.to_string(), primary, verified }
+    }
+
+    #[test]
+    fn verified_user_is_linked_by_email() {
+        assert!(can_link_by_email(&user("victim@example.com", true), "victim@example.com"));
+    }
+
+    #[test]
+    fn unverified_user_is_not_linked_by_email() {
+        // Someone registered the address without owning it, the owner logging in with a provider mustn't end up there
+        assert!(!can_link_by_email(&user("victim@example.com", false), "victim@example.com"));
+    }
+
+    #[test]
+    fn other_email_is_not_linked() {
+        assert!(!can_link_by_email(&user("victim@example.com", true), "someone@example.com"));
+    }
+
+    #[test]
+    fn github_email_has_to_be_primary_and_verified() {
+        assert_eq!(github_email(vec![github("a@example.com", true, false), github("b@example.com", false, true)]), None);
+        assert_eq!(github_email(vec![github("a@example.com", false, true), github("b@example.com", true, true)]), Some("b@example.com".to_string()));
+    }
+
+    #[test]
+    fn discord_email_has_to_be_verified() {
+        assert_eq!(discord_email(Some("a@example.com".to_string()), Some(true)), Some("a@example.com".to_string()));
+        assert_eq!(discord_email(Some("a@example.com".to_string()), Some(false)), None);
+        assert_eq!(discord_email(Some("a@example.com".to_string()), None), None);
+    }
+}
@@ -0,0 +1,108 @@
+use actix_web::{get, HttpResponse, web};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::{Content, Ref, Response};
+use utoipa::{Modify, OpenApi};
+use utoipa_scalar::{Scalar, Servable};
+use crate::error::ErrorBody;
+
+// Where the docs are served, they need their own content security policy
+pub(crate) const DOCS_PATH: &str = "/api/docs";
+
+/// The OpenAPI document of the api, generated from the `#[utoipa::path]` annotations of the handlers
+///
+/// Every module documents its handlers in its own struct, which is nested here under the path of its scope.
+/// `openapi.json` in the repository is a copy of this document, a test fails when it is out of date.
+#[derive(OpenApi)]
+#[openapi(
+    nest(
+        (path = "/api/v1/auth", api = crate::auth::AuthApi),
+        (path = "/api/v1/auth/oauth", api = crate::oauth::OAuthApi),
+        (path = "/api/v1/auth/tokens", api = crate::tokens::TokenApi),
+        (path = "/api/v1/auth/sessions", api = crate::sessions::SessionApi),
+        (path = "/api/v1/users", api = crate::api::users::UserApi),
+        (path = "/api/v1/posts", api = crate::api::post::PostApi),
+        (path = "/api/v1/invites", api = crate::api::invites::InviteApi),
+        (path = "/api/v1/storage", api = crate::api::storage::StorageApi),
+        (path = "/api/v1/audit", api = crate::audit::AuditApi),
+        (path = "/api/v1/lockouts", api = crate::lockout::LockoutApi),
+    ),
+    paths(
+        crate::api::feed::feed_get, crate::api::feed::feed_tag_get, crate::api::feed::feed_project_get,
+        crate::api::seo::sitemap_get, crate::api::seo::meta_get,
+        crate::keys::jwks_get,
+        crate::health::healthz_get, crate::health::readyz_get,
+        crate::metrics::metrics_get,
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&Security, &Errors),
+)]
+pub struct ApiDoc;
+
+// The ways to authenticate, referred to by the handlers as "bearer"
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(Http::builder()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("A login token or a personal access token. With cookie logins the `session` cookie \
+                is used instead, requests which change something then need the `X-CSRF-Token` header."))
+            .build()));
+    }
+}
+
+// Every operation can fail with an ErrorBody, so that is documented once instead of on every handler
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.responses.insert("Error".to_string(), Response::builder()
+            .description("The request failed, `code` tells why")
+            .content("application/json", Content::new(Some(Ref::from_schema_name("ErrorBody"))))
+            .build()
+            .into());
+
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete, &mut item.patch];
+            for operation in operations.into_iter().flatten() {
+                operation.responses.responses.entry("default".to_string())
+                    .or_insert_with(|| Ref::from_response_name("Error").into());
+            }
+        }
+    }
+}
+
+pub fn openapi_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_get)
+        .service(Scalar::with_url(DOCS_PATH, ApiDoc::openapi()));
+}
+
+#[get("/api/openapi.json")]
+async fn openapi_get() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use super::*;
+
+    // Run with UPDATE_OPENAPI=1 to write the current document after changing the api
+    #[test]
+    fn openapi_json_is_up_to_date() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("openapi.json");
+        let generated = ApiDoc::openapi().to_pretty_json().expect("the document can't be serialized") + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            fs::write(&path, &generated).expect("openapi.json can't be written");
+            return;
+        }
+
+        let committed = fs::read_to_string(&path).unwrap_or_default();
+        assert!(committed == generated,
+            "openapi.json doesn't match the handlers anymore, run `UPDATE_OPENAPI=1 cargo test` and commit the result");
+    }
+}
@@ -0,0 +1,61 @@
+use actix_web::web;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use crate::error::ApiError;
+
+/// Hashes a password the way it is stored in the database, argon2id with a random salt
+///
+/// Hashing is slow on purpose, so it runs on the blocking thread pool instead of holding up a worker.
+pub async fn hash_password(password: String) -> Result<String, ApiError> {
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+    })
+        .await
+        .map_err(|err| ApiError::Internal(format!("failed to hash the password: {err}")))?
+        .map_err(|err| ApiError::Internal(format!("failed to hash the password: {err}")))
+}
+
+/// Checks `password` against a hash made by `hash_password`
+///
+/// The parameters are read from the hash, so hashes made with other settings keep working.
+pub async fn verify_password(hash: String, password: String) -> Result<bool, ApiError> {
+    web::block(move || {
+        let Ok(hash) = PasswordHash::new(&hash) else {
+            // Not a hash at all, nothing can match it
+            return false;
+        };
+        Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+    })
+        .await
+        .map_err(|err| ApiError::Internal(format!("failed to check the password: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn hashed_password_is_verified() {
+        let hash = hash_password("correct horse".to_string()).await.unwrap();
+
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password(hash.clone(), "correct horse".to_string()).await.unwrap());
+        assert!(!verify_password(hash, "wrong horse".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn same_password_gets_different_hashes() {
+        let first = hash_password("secret".to_string()).await.unwrap();
+        let second = hash_password("secret".to_string()).await.unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[actix_web::test]
+    async fn plaintext_never_matches() {
+        // A password the migration hasn't hashed yet mustn't let anyone in, not even with itself
+        assert!(!verify_password("secret".to_string(), "secret".to_string()).await.unwrap());
+        assert!(!verify_password(String::new(), String::new()).await.unwrap());
+    }
+}
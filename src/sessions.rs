@@ -0,0 +1,98 @@
+use actix_web::{delete, get, HttpRequest, HttpResponse, Scope, web};
+use actix_web::http::header::USER_AGENT;
+use chrono::{Duration, Utc};
+use tracing::error;
+use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
+use crate::audit::{Auditor, snapshot};
+use crate::definitions::{Session, User};
+use crate::error::ApiError;
+use crate::middleware::client_ip::client_ip;
+use crate::storage::database_manager::DatabaseManager;
+
+// last_seen is only written when it is older than this, not on every request
+const LAST_SEEN_PRECISION_MINUTES: i64 = 5;
+
+// A session as listed to its user
+#[derive(Serialize, ToSchema)]
+struct SessionInfo {
+    #[serde(flatten)]
+    session: Session,
+    // Whether this is the session the list was requested with
+    current: bool,
+}
+
+/// Records a new login of the user from the client which sent `req`, which stays valid for `lifetime`
+pub async fn start_session(database_manager: &DatabaseManager, req: &HttpRequest, user: &User, lifetime: Duration) -> Result<Session, ApiError> {
+    let user_agent = req.headers().get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let ip = client_ip(req).map(|address| address.to_string());
+
+    database_manager.add_session(user, user_agent, ip, Utc::now() + lifetime).await?
+        .ok_or(ApiError::Internal("the session wasn't created".to_string()))
+}
+
+/// Fails with [`ApiError::InvalidToken`] if the session was ended or doesn't belong to the user
+pub async fn check_session(database_manager: &DatabaseManager, session_id: &str, user_id: &str) -> Result<(), ApiError> {
+    let session = database_manager.fetch_session(session_id.to_string()).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    if session.user.to_string() != user_id {
+        return Err(ApiError::InvalidToken);
+    }
+
+    if session.last_seen < Utc::now() - Duration::minutes(LAST_SEEN_PRECISION_MINUTES) {
+        if let Err(err) = database_manager.touch_session(session_id.to_string()).await {
+            error!("Couldn't update the last use of session {}: {}", session_id, err);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(sessions_get, session_delete))]
+pub(crate) struct SessionApi;
+
+// Registered before the auth service, otherwise its scope would swallow these routes
+pub fn session_service() -> Scope {
+    web::scope("/api/v1/auth/sessions")
+        .service(sessions_get)
+        .service(session_delete)
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200, body = Vec<SessionInfo>)), security(("bearer" = [])))]
+#[get("")]
+async fn sessions_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let sessions: Vec<SessionInfo> = database_manager.fetch_sessions(user.id.to_string()).await?
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: user.session.as_deref() == Some(session.id.to_string().as_str()),
+            session,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// Ending the current session logs out
+#[utoipa::path(tag = "auth", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/{sessionId}")]
+async fn session_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let session_id = path.into_inner();
+
+    let session = database_manager.delete_session(user.id.to_string(), session_id).await?
+        .ok_or(ApiError::NotFound)?;
+    auditor.record(&user, "session.revoke", format!("session:{}", session.id), snapshot(&session), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
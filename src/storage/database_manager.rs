@@ -1,66 +1,391 @@
 use std::sync::Arc;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use surrealdb::engine::remote::ws::{Client, Ws};
 use surrealdb::opt::auth::{Root};
-use surrealdb::{Response, Surreal};
-use log::info;
-use crate::definitions::{BodyPost, BodyUser, IntelliThing, Post, User};
+use surrealdb::{Datetime, RecordId, Response, Surreal};
+use surrealdb::sql::Id;
+use chrono::{DateTime, Utc};
+use tracing::{error, info, instrument, warn};
+use tokio::sync::watch;
+use utoipa::IntoParams;
+use crate::metrics::time_query;
+use crate::definitions::{ApiToken, AuditEntry, BodyApiToken, BodyInvite, Session, BodyPost, BodyUser, FeedPost, Identity, Invite, Lockout, Post, PostRevision, ProjectSummary, User};
+
+// Tables, events and indexes the application relies on. Every statement has to be safe to run on every start.
+const DATABASE_DEFINITIONS: &str = r#"
+    DEFINE EVENT OVERWRITE post_revision ON TABLE post
+        WHEN $event = "CREATE" OR ($event = "UPDATE" AND $before.deleted_at = $after.deleted_at AND (
+            $before.title != $after.title OR $before.summary != $after.summary OR $before.content != $after.content
+            OR $before.tags != $after.tags OR $before.project != $after.project OR $after.restored_from != NONE))
+        THEN {
+            CREATE post_revision CONTENT {
+                post: $after.id,
+                editor: $after.editor,
+                created: time::now(),
+                title: $after.title,
+                summary: $after.summary,
+                content: $after.content,
+                tags: $after.tags,
+                project: $after.project,
+                restored_from: $after.restored_from,
+            };
+        };
+    DEFINE INDEX OVERWRITE post_revision_post ON TABLE post_revision FIELDS post;
+
+    DEFINE TABLE OVERWRITE wrote TYPE RELATION FROM user TO post ENFORCED;
+    DEFINE INDEX OVERWRITE wrote_unique ON TABLE wrote FIELDS in, out UNIQUE;
+    DEFINE INDEX OVERWRITE wrote_post ON TABLE wrote FIELDS out;
+
+    -- Posts used to have a single `author` field, move those over to the wrote relation
+    FOR $post IN (SELECT id, author FROM post WHERE type::is::record(author)) {
+        LET $author = $post.author;
+        LET $id = $post.id;
+        -- The relation is enforced, so authors which don't exist anymore can't be moved over
+        IF count(SELECT id FROM wrote WHERE out = $id) = 0 AND record::exists($author) {
+            RELATE $author->wrote->$id SET position = 0;
+        };
+        UPDATE $id UNSET author;
+    };
+
+    -- Passwords used to be stored as they were typed, hash the ones which are left (same argon2 the api uses)
+    UPDATE user SET password = crypto::argon2::generate(password) WHERE type::is::string(password) AND !string::starts_with(password, "$argon2");
+
+    -- Checked before every write as well, these catch two requests which both passed the check at the same time
+    DEFINE INDEX OVERWRITE user_name ON TABLE user FIELDS name UNIQUE;
+    DEFINE INDEX OVERWRITE user_email ON TABLE user FIELDS email UNIQUE;
+
+    DEFINE EVENT OVERWRITE user_password_changed ON TABLE user WHEN $before.password != $after.password THEN {
+        UPDATE $after.id SET password_changed = time::now();
+    };
+
+    DEFINE INDEX OVERWRITE identity_subject ON TABLE identity FIELDS provider, subject UNIQUE;
+    DEFINE INDEX OVERWRITE identity_user ON TABLE identity FIELDS user;
+
+    DEFINE INDEX OVERWRITE session_user ON TABLE session FIELDS user;
+
+    DEFINE INDEX OVERWRITE api_token_hash ON TABLE api_token FIELDS token_hash UNIQUE;
+    DEFINE INDEX OVERWRITE api_token_user ON TABLE api_token FIELDS user;
+
+    DEFINE EVENT OVERWRITE audit_append_only ON TABLE audit WHEN $event != "CREATE" THEN {
+        THROW "The audit log is append-only";
+    };
+    DEFINE INDEX OVERWRITE audit_created ON TABLE audit FIELDS created;
+"#;
+
+// Replaces the authors of `$post` with `$authors`, a list of PostAuthor. Part of the transactions writing posts.
+const SET_AUTHORS: &str = "DELETE wrote WHERE out = $post; \
+    FOR $entry IN $authors { \
+        LET $author = $entry.author; \
+        RELATE $author->wrote->$post SET position = $entry.position; \
+    };";
+
+// Whether an invite can still be used, it isn't used up and hasn't expired
+const INVITE_USABLE: &str = "(!max_uses OR uses < max_uses) AND (!expires OR expires > time::now())";
+
+// Thrown to cancel a registration whose invite can't be used (anymore)
+const INVITE_UNUSABLE: &str = "The invite can't be used";
+
+// Selects every field of a post plus its authors, ordered by their position on the `wrote` relation.
+// Authors in the trash are left out, they show up again once they are restored.
+const POST_FIELDS: &str = "*, (SELECT in, position FROM wrote WHERE out = $parent.id AND !in.deleted_at ORDER BY position ASC).in AS authors";
+
+// Same as POST_FIELDS but only the parts needed by the feeds, with the names of the authors instead of their ids
+// `updated` is the newest of the publishing date and the revisions, which are written on every edit.
+const FEED_POST_FIELDS: &str = "id, title, summary, tags, posted, \
+    time::max(array::concat([posted], (SELECT VALUE created FROM post_revision WHERE post = $parent.id))) AS updated, \
+    (SELECT in.name AS name, position FROM wrote WHERE out = $parent.id AND !in.deleted_at ORDER BY position ASC).name AS author_names";
+
+#[derive(Serialize)]
+struct NewPost {
+    title: String,
+    summary: Option<String>,
+    content: Option<String>,
+    tags: Vec<String>,
+    project: Option<String>,
+    likes: i32,
+    views: i32,
+    posted: Datetime,
+    editor: RecordId,
+}
+
+#[derive(Serialize)]
+struct NewUser {
+    name: String,
+    email: String,
+    email_verified: bool,
+    password: String,
+    admin: bool,
+}
+
+#[derive(Serialize)]
+struct NewIdentity {
+    provider: String,
+    subject: String,
+    username: String,
+    user: RecordId,
+    created: Datetime,
+}
+
+#[derive(Serialize)]
+struct NewSession {
+    user: RecordId,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created: Datetime,
+    last_seen: Datetime,
+    expires: Datetime,
+}
+
+#[derive(Serialize)]
+struct NewApiToken {
+    user: RecordId,
+    name: String,
+    scopes: Vec<String>,
+    token_hash: String,
+    prefix: String,
+    created: Datetime,
+    expires: Option<Datetime>,
+}
+
+#[derive(Serialize)]
+struct NewInvite {
+    created_by: RecordId,
+    created: Datetime,
+    expires: Option<Datetime>,
+    max_uses: Option<i64>,
+    uses: i64,
+    admin: bool,
+}
+
+#[derive(Serialize)]
+struct NewAuditEntry {
+    actor: RecordId,
+    action: String,
+    target: String,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+    ip: Option<String>,
+    created: Datetime,
+}
+
+#[derive(Serialize)]
+struct PostAuthor {
+    author: RecordId,
+    position: usize,
+}
+
+fn post_authors(authors: Vec<String>) -> Vec<PostAuthor> {
+    authors.into_iter()
+        .enumerate()
+        .map(|(position, author)| PostAuthor { author: RecordId::from_table_key("user", author), position })
+        .collect()
+}
+
+// In a failed transaction every statement reports an error, only the one which made it fail tells why
+fn transaction_error(mut response: Response) -> Option<surrealdb::Error> {
+    let mut errors: Vec<(usize, surrealdb::Error)> = response.take_errors().into_iter().collect();
+    errors.sort_by_key(|(index, _)| *index);
+
+    let cause = errors.iter()
+        .position(|(_, err)| !err.to_string().contains("not executed due to a failed transaction"))
+        .unwrap_or(0);
+
+    errors.into_iter().nth(cause).map(|(_, err)| err)
+}
+
+// The editable fields of a post, merged into the record on every edit.
+// Setting `restored_from` to NONE removes the marker of a previous restore.
+#[derive(Serialize)]
+struct PostUpdate {
+    title: String,
+    summary: Option<String>,
+    content: Option<String>,
+    tags: Vec<String>,
+    project: Option<String>,
+    editor: RecordId,
+    restored_from: Option<RecordId>,
+}
+
+/// Records removed for good by [`DatabaseManager::purge_trash`]
+pub struct PurgedTrash {
+    pub(crate) users: Vec<User>,
+    pub(crate) posts: Vec<Post>,
+}
+
+// How often the connection is checked once it is established
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+// Bounds of the backoff between attempts to connect at startup
+const MIN_CONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Where the database is and how to log in to it
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    pub(crate) host: String,
+    pub(crate) user: String,
+    pub(crate) password: String,
+    pub(crate) namespace: String,
+    pub(crate) database: String,
+}
 
 #[derive(Clone)]
 pub struct DatabaseManager {
     database: Arc<Surreal<Client>>,
+    settings: Arc<ConnectionSettings>,
+    // Whether the database can currently be used, see maintain_connection
+    available: Arc<watch::Sender<bool>>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct PaginationParams {
     pub(crate) page: Option<i64>,
     pub(crate) per_page: Option<i64>,
 }
 
+impl PaginationParams {
+    const DEFAULT_PER_PAGE: i64 = 50;
+    const MAX_PER_PAGE: i64 = 200;
+
+    pub fn limit(&self) -> i64 {
+        self.per_page.unwrap_or(Self::DEFAULT_PER_PAGE).clamp(1, Self::MAX_PER_PAGE)
+    }
+
+    // Pages start at 1
+    pub fn start(&self) -> i64 {
+        (self.page.unwrap_or(1).max(1) - 1) * self.limit()
+    }
+}
+
 impl DatabaseManager {
-    pub(crate) async fn init(db_host: String, db_name: String, db_pass: String, db_database: String, db_namespace: String) -> surrealdb::Result<Self> {
-        info!("Connecting to database {}@{} with user {}...", db_database, db_host, db_name);
-        // Connect to the database
-        let db = Surreal::new::<Ws>(db_host).await?;
+    /// Creates a manager which isn't connected yet, the connection is made by [`DatabaseManager::maintain_connection`]
+    pub(crate) fn new(settings: ConnectionSettings) -> Self {
+        let (available, _) = watch::channel(false);
 
-        // Select the namespace and database
-        //TODO Do we also want a env var for the namespace?
-        db.use_ns(db_namespace).use_db(db_database).await?;
+        Self {
+            database: Arc::new(Surreal::init()),
+            settings: Arc::new(settings),
+            available: Arc::new(available),
+        }
+    }
 
-        //TODO support more than just root auth
-        db.signin(Root {
-            username: &db_name,
-            password: &db_pass,
-        }).await?;
+    /// Connects to the database and keeps checking the connection, runs until the server shuts down
+    ///
+    /// Connecting is retried with an increasing delay until it succeeds. Afterwards the client reconnects on its own
+    /// when the websocket drops, while it does the database is marked as unavailable. Once it answers again the
+    /// session is set up anew, in case the database was restarted.
+    pub(crate) async fn maintain_connection(self) {
+        let mut delay = MIN_CONNECT_DELAY;
+
+        while let Err(err) = self.connect().await {
+            warn!("Couldn't connect to the database, retrying in {} seconds: {}", delay.as_secs(), err);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_CONNECT_DELAY);
+        }
+
+        self.available.send_replace(true);
+        info!("Connected to the database");
+
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let healthy = matches!(tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.database.health()).await, Ok(Ok(())));
+
+            if !healthy && self.is_available() {
+                warn!("Lost the connection to the database");
+                self.available.send_replace(false);
+            } else if healthy && !self.is_available() {
+                match self.sign_in().await {
+                    Ok(()) => {
+                        info!("Reconnected to the database");
+                        self.available.send_replace(true);
+                    }
+                    Err(err) => error!("Couldn't sign in to the database again: {}", err),
+                }
+            }
+        }
+    }
+
+    /// Whether the database can currently be used
+    pub fn is_available(&self) -> bool {
+        *self.available.borrow()
+    }
+
+    /// Changes whenever the database becomes available or unavailable
+    pub fn availability(&self) -> watch::Receiver<bool> {
+        self.available.subscribe()
+    }
+
+    /// Whether the database answers right now, unlike [`DatabaseManager::is_available`] this asks it
+    #[instrument(skip_all)]
+    pub async fn ping(&self) -> bool {
+        if !self.is_available() {
+            return false;
+        }
+
+        let _timer = time_query("ping");
+        matches!(tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.database.health()).await, Ok(Ok(())))
+    }
+
+    async fn connect(&self) -> surrealdb::Result<()> {
+        info!("Connecting to database {}@{} with user {}...", self.settings.database, self.settings.host, self.settings.user);
+
+        // A failed attempt leaves the client unconnected so it can be tried again, a successful one can't be repeated
+        match self.database.connect::<Ws>(self.settings.host.as_str()).await {
+            Ok(()) | Err(surrealdb::Error::Api(surrealdb::error::Api::AlreadyConnected)) => {}
+            Err(err) => return Err(err),
+        }
+        self.sign_in().await?;
 
-        info!("Connecting successful!");
         info!("Initializing database...");
+        self.database.query(DATABASE_DEFINITIONS).await?.check()?;
 
-        //TODO Initialize the tables, the relations and the events needed for the application
-        let database = Arc::new(db);
-        Ok(Self { database })
+        Ok(())
     }
 
+    async fn sign_in(&self) -> surrealdb::Result<()> {
+        self.database.use_ns(self.settings.namespace.as_str()).use_db(self.settings.database.as_str()).await?;
+
+        //TODO support more than just root auth
+        self.database.signin(Root {
+            username: &self.settings.user,
+            password: &self.settings.password,
+        }).await?;
+
+        Ok(())
+    }
+
+    #[allow(dead_code)]
     pub fn get_database(&self) -> Arc<Surreal<Client>> {
         self.database.clone()
     }
 
+    #[allow(dead_code)]
+    #[instrument(skip_all)]
     pub async fn query(&self, query: String, bindings: impl Serialize + 'static) -> surrealdb::Result<Response> {
+        let _timer = time_query("query");
         self.database.query(query).bind(bindings).await
     }
 
+    #[instrument(skip_all)]
     pub async fn fetch_users(&self) -> surrealdb::Result<Vec<User>> {
+        let _timer = time_query("fetch_users");
         let users: Vec<User> = self.database
-            .query("SELECT * FROM user ORDER BY name ASC")
+            .query("SELECT * FROM user WHERE !deleted_at ORDER BY name ASC")
             .await?
             .take(0)?;
 
         Ok(users)
     }
 
+    #[instrument(skip_all)]
     pub async fn fetch_user(&self, name_or_email: String) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("fetch_user");
         let user: Vec<User> = self.database
-            .query("SELECT * FROM user WHERE name = $name OR email = $name OR id = type::thing(\"user\", $name) LIMIT 1")
+            .query("SELECT * FROM user WHERE (name = $name OR email = $name OR id = type::thing(\"user\", $name)) AND !deleted_at LIMIT 1")
             .bind(("name", name_or_email))
             .await?
             .take(0)?;
@@ -68,18 +393,97 @@ impl DatabaseManager {
         Ok(user.into_iter().nth(0))
     }
 
+    /// Like [`DatabaseManager::fetch_user`] but without the email, for routes everyone can call
+    #[instrument(skip_all)]
+    pub async fn fetch_public_user(&self, name_or_id: String) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("fetch_public_user");
+        let user: Vec<User> = self.database
+            .query("SELECT * FROM user WHERE (name = $name OR id = type::thing(\"user\", $name)) AND !deleted_at LIMIT 1")
+            .bind(("name", name_or_id))
+            .await?
+            .take(0)?;
+
+        Ok(user.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
     pub async fn fetch_posts(&self) -> surrealdb::Result<Vec<Post>> {
+        let _timer = time_query("fetch_posts");
         let posts: Vec<Post> = self.database
-            .query("SELECT * FROM post ORDER BY posted ASC")
+            .query(format!("SELECT {POST_FIELDS} FROM post WHERE !deleted_at ORDER BY posted ASC"))
+            .await?
+            .take(0)?;
+
+        Ok(posts)
+    }
+
+    /// Fetches the newest published posts, optionally limited to a tag or a project
+    ///
+    /// A post counts as published once its `posted` date has passed.
+    #[instrument(skip_all)]
+    pub async fn fetch_published_posts(&self, tag: Option<String>, project: Option<String>, limit: i64) -> surrealdb::Result<Vec<FeedPost>> {
+        let _timer = time_query("fetch_published_posts");
+        let posts: Vec<FeedPost> = self.database
+            .query(format!("SELECT {FEED_POST_FIELDS} FROM post \
+                    WHERE posted <= time::now() AND !deleted_at AND (!$tag OR $tag IN tags) AND (!$project OR project = $project) \
+                    ORDER BY posted DESC LIMIT $limit"))
+            .bind(("tag", tag))
+            .bind(("project", project))
+            .bind(("limit", limit))
             .await?
             .take(0)?;
 
         Ok(posts)
     }
 
+    #[instrument(skip_all)]
+    pub async fn fetch_published_post(&self, id: String) -> surrealdb::Result<Option<FeedPost>> {
+        let _timer = time_query("fetch_published_post");
+        let post: Vec<FeedPost> = self.database
+            .query(format!("SELECT {FEED_POST_FIELDS} FROM post \
+                    WHERE id = type::thing(\"post\", $id) AND posted <= time::now() AND !deleted_at LIMIT 1"))
+            .bind(("id", id))
+            .await?
+            .take(0)?;
+
+        Ok(post.into_iter().nth(0))
+    }
+
+    /// When the last published post was moved to the trash, optionally only counting posts with `tag` or in `project`
+    ///
+    /// Removed posts don't show up in the feeds anymore, this still lets them tell that the feed changed.
+    #[instrument(skip_all)]
+    pub async fn fetch_last_post_deletion(&self, tag: Option<String>, project: Option<String>) -> surrealdb::Result<Option<DateTime<Utc>>> {
+        let _timer = time_query("fetch_last_post_deletion");
+        let deleted: Vec<DateTime<Utc>> = self.database
+            .query("SELECT VALUE deleted_at FROM post \
+                    WHERE deleted_at AND posted <= deleted_at AND (!$tag OR $tag IN tags) AND (!$project OR project = $project) \
+                    ORDER BY deleted_at DESC LIMIT 1")
+            .bind(("tag", tag))
+            .bind(("project", project))
+            .await?
+            .take(0)?;
+
+        Ok(deleted.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_projects(&self) -> surrealdb::Result<Vec<ProjectSummary>> {
+        let _timer = time_query("fetch_projects");
+        let projects: Vec<ProjectSummary> = self.database
+            .query("SELECT project, time::max(posted) AS updated FROM post \
+                    WHERE posted <= time::now() AND !deleted_at AND project GROUP BY project ORDER BY project ASC")
+            .await?
+            .take(0)?;
+
+        Ok(projects)
+    }
+
+    #[instrument(skip_all)]
     pub async fn fetch_post(&self, title_or_id: String) -> surrealdb::Result<Option<Post>> {
+        let _timer = time_query("fetch_post");
         let post: Vec<Post> = self.database
-            .query("SELECT * FROM post WHERE title = $name OR id = type::thing(\"post\", $name) LIMIT 1")
+            .query(format!("SELECT {POST_FIELDS} FROM post WHERE (title = $name OR id = type::thing(\"post\", $name)) AND !deleted_at LIMIT 1"))
             .bind(("name", title_or_id))
             .await?
             .take(0)?;
@@ -87,31 +491,706 @@ impl DatabaseManager {
         Ok(post.into_iter().nth(0))
     }
 
+    /// Writes the editable fields of `post` back to the database, which records a new revision
+    ///
+    /// `authors` replaces the authors in the same transaction, in the order they are credited in.
+    /// `restored_from` marks the revision the content was taken from when restoring an older revision.
+    #[instrument(skip_all)]
+    pub async fn update_post(&self, post: &Post, authors: Option<Vec<String>>, editor: &User, restored_from: Option<String>) -> surrealdb::Result<Option<Post>> {
+        let _timer = time_query("update_post");
+        let set_authors = if authors.is_some() { SET_AUTHORS } else { "" };
+
+        self.database
+            .query(format!("BEGIN TRANSACTION; \
+                    LET $post = type::thing(\"post\", $id); \
+                    UPDATE $post MERGE $changes; \
+                    {set_authors} \
+                    COMMIT TRANSACTION;"))
+            .bind(("id", post.id.to_string()))
+            .bind(("changes", PostUpdate {
+                title: post.title.clone(),
+                summary: post.summary.clone(),
+                content: post.content.clone(),
+                tags: post.tags.clone(),
+                project: post.project.clone(),
+                editor: RecordId::from_table_key("user", editor.id.to_string()),
+                restored_from: restored_from.map(|revision| RecordId::from_table_key("post_revision", revision)),
+            }))
+            .bind(("authors", post_authors(authors.unwrap_or_default())))
+            .await?
+            .check()?;
+
+        // The update only returns the stored fields, so fetch it again to get the authors
+        self.fetch_post(post.id.to_string()).await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_revisions(&self, post_id: String) -> surrealdb::Result<Vec<PostRevision>> {
+        let _timer = time_query("fetch_revisions");
+        let revisions: Vec<PostRevision> = self.database
+            .query("SELECT * FROM post_revision WHERE post = type::thing(\"post\", $post) ORDER BY created DESC")
+            .bind(("post", post_id))
+            .await?
+            .take(0)?;
+
+        Ok(revisions)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_revision(&self, post_id: String, revision_id: String) -> surrealdb::Result<Option<PostRevision>> {
+        let _timer = time_query("fetch_revision");
+        let revision: Vec<PostRevision> = self.database
+            .query("SELECT * FROM type::thing(\"post_revision\", $revision) WHERE post = type::thing(\"post\", $post)")
+            .bind(("post", post_id))
+            .bind(("revision", revision_id))
+            .await?
+            .take(0)?;
+
+        Ok(revision.into_iter().nth(0))
+    }
+
+    /// Moves a user to the trash, returns the user as it was before
+    ///
+    /// The posts of the user stay where they are, the user is just no longer listed as one of their authors.
+    #[instrument(skip_all)]
     pub async fn delete_user(&self, id: String) -> surrealdb::Result<Option<User>> {
-        let deleted: Option<User> = self.database.delete(("user", id)).await?;
-        Ok(deleted)
+        let _timer = time_query("delete_user");
+        let deleted: Vec<User> = self.database
+            .query("UPDATE type::thing(\"user\", $id) SET deleted_at = time::now() WHERE !deleted_at RETURN BEFORE")
+            .bind(("id", id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted.into_iter().nth(0))
     }
 
+    /// Moves a post to the trash, returns the post as it was before
+    #[instrument(skip_all)]
     pub async fn delete_post(&self, id: String) -> surrealdb::Result<Option<Post>> {
-        let deleted: Option<Post> = self.database.delete(("post", id)).await?;
-        Ok(deleted)
+        let _timer = time_query("delete_post");
+        let deleted: Vec<Post> = self.database
+            .query(format!("SELECT {POST_FIELDS} FROM type::thing(\"post\", $id) WHERE !deleted_at; \
+                    UPDATE type::thing(\"post\", $id) SET deleted_at = time::now() WHERE !deleted_at;"))
+            .bind(("id", id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_deleted_users(&self) -> surrealdb::Result<Vec<User>> {
+        let _timer = time_query("fetch_deleted_users");
+        let users: Vec<User> = self.database
+            .query("SELECT * FROM user WHERE deleted_at ORDER BY deleted_at DESC")
+            .await?
+            .take(0)?;
+
+        Ok(users)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_deleted_posts(&self) -> surrealdb::Result<Vec<Post>> {
+        let _timer = time_query("fetch_deleted_posts");
+        let posts: Vec<Post> = self.database
+            .query(format!("SELECT {POST_FIELDS} FROM post WHERE deleted_at ORDER BY deleted_at DESC"))
+            .await?
+            .take(0)?;
+
+        Ok(posts)
+    }
+
+    /// Takes a user out of the trash
+    #[instrument(skip_all)]
+    pub async fn restore_user(&self, id: String) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("restore_user");
+        let restored: Vec<User> = self.database
+            .query("UPDATE type::thing(\"user\", $id) UNSET deleted_at WHERE deleted_at RETURN AFTER")
+            .bind(("id", id))
+            .await?
+            .take(0)?;
+
+        Ok(restored.into_iter().nth(0))
+    }
+
+    /// Takes a post out of the trash
+    #[instrument(skip_all)]
+    pub async fn restore_post(&self, id: String) -> surrealdb::Result<Option<Post>> {
+        let _timer = time_query("restore_post");
+        let restored: Vec<Post> = self.database
+            .query(format!("LET $restored = (UPDATE type::thing(\"post\", $id) UNSET deleted_at WHERE deleted_at RETURN VALUE id); \
+                    SELECT {POST_FIELDS} FROM $restored;"))
+            .bind(("id", id))
+            .await?
+            .take(1)?;
+
+        Ok(restored.into_iter().nth(0))
+    }
+
+    /// Permanently deletes everything which has been in the trash since before `deleted_before`
+    ///
+    /// The revisions of purged posts as well as the linked identities, tokens, sessions and lockouts of purged users go with them. Deleting a user or post also removes its `wrote` relations,
+    /// so posts of a purged user are only credited to their remaining authors.
+    #[instrument(skip_all)]
+    pub async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> surrealdb::Result<PurgedTrash> {
+        let _timer = time_query("purge_trash");
+        let mut response = self.database
+            .query("BEGIN TRANSACTION; \
+                    DELETE post_revision WHERE post.deleted_at != NONE AND post.deleted_at < $cutoff; \
+                    DELETE post WHERE deleted_at != NONE AND deleted_at < $cutoff RETURN BEFORE; \
+                    DELETE identity WHERE user.deleted_at != NONE AND user.deleted_at < $cutoff; \
+                    DELETE api_token WHERE user.deleted_at != NONE AND user.deleted_at < $cutoff; \
+                    DELETE session WHERE user.deleted_at != NONE AND user.deleted_at < $cutoff; \
+                    DELETE lockout WHERE record::id(id) IN \
+                        (SELECT VALUE record::id(id) FROM user WHERE deleted_at != NONE AND deleted_at < $cutoff); \
+                    DELETE user WHERE deleted_at != NONE AND deleted_at < $cutoff RETURN BEFORE; \
+                    COMMIT TRANSACTION;")
+            .bind(("cutoff", Datetime::from(deleted_before)))
+            .await?;
+
+        let posts: Vec<Post> = response.take(1)?;
+        let users: Vec<User> = response.take(6)?;
+
+        Ok(PurgedTrash { users, posts })
     }
 
+    #[instrument(skip_all)]
     pub async fn add_user(&self, user: BodyUser) -> surrealdb::Result<Vec<User>> {
+        let _timer = time_query("add_user");
         self.database
             .insert("user")
             .content(user)
             .await
     }
 
-    pub async fn add_post(&self, post: BodyPost) -> surrealdb::Result<Vec<Post>> {
+    /// Creates an account registered by the user themselves
+    ///
+    /// With an `invite` the account is only created if the invite can still be used, which also decides if the user is
+    /// an admin. The invite is counted in the same transaction as the user is created, so a registration which fails
+    /// doesn't use it up. Returns nothing if the invite couldn't be used.
+    #[instrument(skip_all)]
+    pub async fn register_user(&self, name: String, email: String, email_verified: bool, password: String, invite: Option<String>) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("register_user");
+        // Chosen up front, so the user can be fetched after the transaction
+        let id = Id::rand().to_raw();
+
+        let response = self.database
+            .query(format!("BEGIN TRANSACTION; \
+                    LET $user = type::thing(\"user\", $id); \
+                    CREATE $user CONTENT $content; \
+                    IF $invite {{ \
+                        LET $used = (UPDATE type::thing(\"invite\", $invite) SET uses += 1 WHERE {INVITE_USABLE} RETURN AFTER)[0]; \
+                        IF !$used {{ THROW \"{INVITE_UNUSABLE}\"; }}; \
+                        UPDATE $user SET admin = $used.admin; \
+                    }}; \
+                    COMMIT TRANSACTION;"))
+            .bind(("id", id.clone()))
+            .bind(("invite", invite))
+            .bind(("content", NewUser {
+                name,
+                email,
+                email_verified,
+                password,
+                admin: false,
+            }))
+            .await?;
+
+        match transaction_error(response) {
+            Some(err) if err.to_string().contains(INVITE_UNUSABLE) => return Ok(None),
+            Some(err) => return Err(err),
+            None => {}
+        }
+
+        self.fetch_user(id).await
+    }
+
+    /// Checks if any other user than `except`, including the ones in the trash, already uses `name` or `email` as their name or email
+    #[instrument(skip_all)]
+    pub async fn is_user_taken(&self, name: String, email: String, except: Option<String>) -> surrealdb::Result<bool> {
+        let _timer = time_query("is_user_taken");
+        let taken: Vec<User> = self.database
+            .query("SELECT * FROM user WHERE (name IN [$name, $email] OR email IN [$name, $email]) \
+                    AND (!$except OR id != type::thing(\"user\", $except)) LIMIT 1")
+            .bind(("name", name))
+            .bind(("email", email))
+            .bind(("except", except))
+            .await?
+            .take(0)?;
+
+        Ok(!taken.is_empty())
+    }
+
+    /// Fetches the user an account at an external provider is linked to
+    #[instrument(skip_all)]
+    pub async fn fetch_identity_user(&self, provider: String, subject: String) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("fetch_identity_user");
+        let user: Vec<User> = self.database
+            .query("SELECT * FROM user WHERE id IN (SELECT VALUE user FROM identity WHERE provider = $provider AND subject = $subject) \
+                    AND !deleted_at LIMIT 1")
+            .bind(("provider", provider))
+            .bind(("subject", subject))
+            .await?
+            .take(0)?;
+
+        Ok(user.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_identities(&self, user_id: String) -> surrealdb::Result<Vec<Identity>> {
+        let _timer = time_query("fetch_identities");
+        let identities: Vec<Identity> = self.database
+            .query("SELECT * FROM identity WHERE user = type::thing(\"user\", $user) ORDER BY provider ASC")
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(identities)
+    }
+
+    /// Links an account at an external provider to a user, an account can only be linked to one user
+    #[instrument(skip_all)]
+    pub async fn link_identity(&self, provider: String, subject: String, username: String, user_id: String) -> surrealdb::Result<Option<Identity>> {
+        let _timer = time_query("link_identity");
+        self.database
+            .create("identity")
+            .content(NewIdentity {
+                provider,
+                subject,
+                username,
+                user: RecordId::from_table_key("user", user_id),
+                created: Datetime::from(Utc::now()),
+            })
+            .await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn unlink_identity(&self, user_id: String, provider: String) -> surrealdb::Result<Vec<Identity>> {
+        let _timer = time_query("unlink_identity");
+        let identities: Vec<Identity> = self.database
+            .query("DELETE identity WHERE user = type::thing(\"user\", $user) AND provider = $provider RETURN BEFORE")
+            .bind(("user", user_id))
+            .bind(("provider", provider))
+            .await?
+            .take(0)?;
+
+        Ok(identities)
+    }
+
+    /// Stores the secret of a two-factor enrolment until it is confirmed
+    #[instrument(skip_all)]
+    pub async fn set_totp_pending(&self, user_id: String, secret: String) -> surrealdb::Result<()> {
+        let _timer = time_query("set_totp_pending");
+        self.database
+            .query("UPDATE type::thing(\"user\", $id) SET totp_pending = $secret")
+            .bind(("id", user_id))
+            .bind(("secret", secret))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Turns on two-factor authentication, `recovery_codes` are the hashes of the recovery codes
+    #[instrument(skip_all)]
+    pub async fn enable_totp(&self, user_id: String, secret: String, recovery_codes: Vec<String>) -> surrealdb::Result<()> {
+        let _timer = time_query("enable_totp");
+        self.database
+            .query("UPDATE type::thing(\"user\", $id) SET two_factor = true, totp_secret = $secret, \
+                    totp_recovery_codes = $codes, totp_pending = NONE, totp_last_step = NONE")
+            .bind(("id", user_id))
+            .bind(("secret", secret))
+            .bind(("codes", recovery_codes))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn disable_totp(&self, user_id: String) -> surrealdb::Result<()> {
+        let _timer = time_query("disable_totp");
+        self.database
+            .query("UPDATE type::thing(\"user\", $id) SET two_factor = false, totp_secret = NONE, \
+                    totp_recovery_codes = NONE, totp_pending = NONE, totp_last_step = NONE")
+            .bind(("id", user_id))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Marks the time step of a code as used, returns false if a code of this or a later step was used before
+    #[instrument(skip_all)]
+    pub async fn use_totp_step(&self, user_id: String, step: i64) -> surrealdb::Result<bool> {
+        let _timer = time_query("use_totp_step");
+        let updated: Vec<User> = self.database
+            .query("UPDATE type::thing(\"user\", $id) SET totp_last_step = $step \
+                    WHERE totp_last_step = NONE OR totp_last_step < $step RETURN AFTER")
+            .bind(("id", user_id))
+            .bind(("step", step))
+            .await?
+            .take(0)?;
+
+        Ok(!updated.is_empty())
+    }
+
+    /// Removes a recovery code by its hash, returns false if the user doesn't have this code
+    #[instrument(skip_all)]
+    pub async fn use_recovery_code(&self, user_id: String, code_hash: String) -> surrealdb::Result<bool> {
+        let _timer = time_query("use_recovery_code");
+        let updated: Vec<User> = self.database
+            .query("UPDATE type::thing(\"user\", $id) SET totp_recovery_codes -= $code \
+                    WHERE $code IN totp_recovery_codes RETURN AFTER")
+            .bind(("id", user_id))
+            .bind(("code", code_hash))
+            .await?
+            .take(0)?;
+
+        Ok(!updated.is_empty())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn add_session(&self, user: &User, user_agent: Option<String>, ip: Option<String>, expires: DateTime<Utc>) -> surrealdb::Result<Option<Session>> {
+        let _timer = time_query("add_session");
+        let now = Datetime::from(Utc::now());
+
+        self.database
+            .create("session")
+            .content(NewSession {
+                user: RecordId::from_table_key("user", user.id.to_string()),
+                user_agent,
+                ip,
+                created: now.clone(),
+                last_seen: now,
+                expires: Datetime::from(expires),
+            })
+            .await
+    }
+
+    /// Fetches a session which hasn't expired yet
+    #[instrument(skip_all)]
+    pub async fn fetch_session(&self, session_id: String) -> surrealdb::Result<Option<Session>> {
+        let _timer = time_query("fetch_session");
+        let session: Vec<Session> = self.database
+            .query("SELECT * FROM type::thing(\"session\", $id) WHERE expires > time::now()")
+            .bind(("id", session_id))
+            .await?
+            .take(0)?;
+
+        Ok(session.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_sessions(&self, user_id: String) -> surrealdb::Result<Vec<Session>> {
+        let _timer = time_query("fetch_sessions");
+        let sessions: Vec<Session> = self.database
+            .query("SELECT * FROM session WHERE user = type::thing(\"user\", $user) AND expires > time::now() ORDER BY last_seen DESC")
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(sessions)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn touch_session(&self, session_id: String) -> surrealdb::Result<()> {
+        let _timer = time_query("touch_session");
+        self.database
+            .query("UPDATE type::thing(\"session\", $id) SET last_seen = time::now()")
+            .bind(("id", session_id))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Ends a session of the user, returns `None` if the user has no such session
+    #[instrument(skip_all)]
+    pub async fn delete_session(&self, user_id: String, session_id: String) -> surrealdb::Result<Option<Session>> {
+        let _timer = time_query("delete_session");
+        let deleted: Vec<Session> = self.database
+            .query("DELETE type::thing(\"session\", $id) WHERE user = type::thing(\"user\", $user) RETURN BEFORE")
+            .bind(("id", session_id))
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted.into_iter().nth(0))
+    }
+
+    /// Ends every session of the user and returns them
+    #[instrument(skip_all)]
+    pub async fn delete_sessions(&self, user_id: String) -> surrealdb::Result<Vec<Session>> {
+        let _timer = time_query("delete_sessions");
+        let deleted: Vec<Session> = self.database
+            .query("DELETE session WHERE user = type::thing(\"user\", $user) RETURN BEFORE")
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn delete_expired_sessions(&self) -> surrealdb::Result<()> {
+        let _timer = time_query("delete_expired_sessions");
+        self.database
+            .query("DELETE session WHERE expires < time::now()")
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_api_tokens(&self, user_id: String) -> surrealdb::Result<Vec<ApiToken>> {
+        let _timer = time_query("fetch_api_tokens");
+        let tokens: Vec<ApiToken> = self.database
+            .query("SELECT * FROM api_token WHERE user = type::thing(\"user\", $user) ORDER BY created DESC")
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(tokens)
+    }
+
+    /// Fetches a token which hasn't expired yet by the hash of the token
+    #[instrument(skip_all)]
+    pub async fn fetch_api_token(&self, token_hash: String) -> surrealdb::Result<Option<ApiToken>> {
+        let _timer = time_query("fetch_api_token");
+        let token: Vec<ApiToken> = self.database
+            .query("SELECT * FROM api_token WHERE token_hash = $hash AND (!expires OR expires > time::now()) LIMIT 1")
+            .bind(("hash", token_hash))
+            .await?
+            .take(0)?;
+
+        Ok(token.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn add_api_token(&self, token: BodyApiToken, token_hash: String, prefix: String, user: &User) -> surrealdb::Result<Option<ApiToken>> {
+        let _timer = time_query("add_api_token");
         self.database
-            .insert("post")
-            .content(post)
+            .create("api_token")
+            .content(NewApiToken {
+                user: RecordId::from_table_key("user", user.id.to_string()),
+                name: token.name,
+                scopes: token.scopes,
+                token_hash,
+                prefix,
+                created: Datetime::from(Utc::now()),
+                expires: token.expires.map(Datetime::from),
+            })
             .await
     }
 
+    #[instrument(skip_all)]
+    pub async fn touch_api_token(&self, token_id: String) -> surrealdb::Result<()> {
+        let _timer = time_query("touch_api_token");
+        self.database
+            .query("UPDATE type::thing(\"api_token\", $id) SET last_used = time::now()")
+            .bind(("id", token_id))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Revokes every token of the user, returns the revoked ones
+    #[instrument(skip_all)]
+    pub async fn delete_api_tokens(&self, user_id: String) -> surrealdb::Result<Vec<ApiToken>> {
+        let _timer = time_query("delete_api_tokens");
+        let deleted: Vec<ApiToken> = self.database
+            .query("DELETE api_token WHERE user = type::thing(\"user\", $user) RETURN BEFORE")
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted)
+    }
+
+    /// Revokes a token of the user, returns `None` if the user has no such token
+    #[instrument(skip_all)]
+    pub async fn delete_api_token(&self, user_id: String, token_id: String) -> surrealdb::Result<Option<ApiToken>> {
+        let _timer = time_query("delete_api_token");
+        let deleted: Vec<ApiToken> = self.database
+            .query("DELETE type::thing(\"api_token\", $id) WHERE user = type::thing(\"user\", $user) RETURN BEFORE")
+            .bind(("id", token_id))
+            .bind(("user", user_id))
+            .await?
+            .take(0)?;
+
+        Ok(deleted.into_iter().nth(0))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_lockouts(&self) -> surrealdb::Result<Vec<Lockout>> {
+        let _timer = time_query("fetch_lockouts");
+        let lockouts: Vec<Lockout> = self.database
+            .query("SELECT * FROM lockout ORDER BY last_failure DESC")
+            .await?
+            .take(0)?;
+
+        Ok(lockouts)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_lockout(&self, user_id: String) -> surrealdb::Result<Option<Lockout>> {
+        let _timer = time_query("fetch_lockout");
+        self.database.select(("lockout", user_id)).await
+    }
+
+    /// Counts a failed login of the user, returns the number of failures in a row
+    ///
+    /// Failures older than `forget_after` don't count anymore, the count starts over.
+    #[instrument(skip_all)]
+    pub async fn add_login_failure(&self, user_id: String, forget_after: chrono::Duration) -> surrealdb::Result<i64> {
+        let _timer = time_query("add_login_failure");
+        let lockout: Vec<Lockout> = self.database
+            .query("UPSERT type::thing(\"lockout\", $user) SET \
+                    failures = IF last_failure != NONE AND last_failure > $forget_before THEN failures + 1 ELSE 1 END, \
+                    last_failure = time::now() RETURN AFTER")
+            .bind(("user", user_id))
+            .bind(("forget_before", Datetime::from(Utc::now() - forget_after)))
+            .await?
+            .take(0)?;
+
+        Ok(lockout.into_iter().nth(0).map(|lockout| lockout.failures).unwrap_or(1))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn lock_user(&self, user_id: String, until: DateTime<Utc>) -> surrealdb::Result<()> {
+        let _timer = time_query("lock_user");
+        self.database
+            .query("UPDATE type::thing(\"lockout\", $user) SET locked_until = $until")
+            .bind(("user", user_id))
+            .bind(("until", Datetime::from(until)))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn delete_lockout(&self, user_id: String) -> surrealdb::Result<Option<Lockout>> {
+        let _timer = time_query("delete_lockout");
+        self.database.delete(("lockout", user_id)).await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_invites(&self) -> surrealdb::Result<Vec<Invite>> {
+        let _timer = time_query("fetch_invites");
+        let invites: Vec<Invite> = self.database
+            .query("SELECT * FROM invite ORDER BY created DESC")
+            .await?
+            .take(0)?;
+
+        Ok(invites)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn add_invite(&self, code: String, invite: BodyInvite, creator: &User) -> surrealdb::Result<Option<Invite>> {
+        let _timer = time_query("add_invite");
+        self.database
+            .create(("invite", code))
+            .content(NewInvite {
+                created_by: RecordId::from_table_key("user", creator.id.to_string()),
+                created: Datetime::from(Utc::now()),
+                expires: invite.expires.map(Datetime::from),
+                max_uses: invite.max_uses,
+                uses: 0,
+                admin: invite.admin,
+            })
+            .await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn delete_invite(&self, code: String) -> surrealdb::Result<Option<Invite>> {
+        let _timer = time_query("delete_invite");
+        self.database.delete(("invite", code)).await
+    }
+
+    /// Fetches the posts `user_id` is one of the authors of, newest first
+    #[instrument(skip_all)]
+    pub async fn fetch_user_posts(&self, user_id: String, include_unpublished: bool) -> surrealdb::Result<Vec<Post>> {
+        let _timer = time_query("fetch_user_posts");
+        let posts: Vec<Post> = self.database
+            .query(format!("SELECT {POST_FIELDS} FROM post \
+                    WHERE type::thing(\"user\", $user) IN <-wrote<-user AND !deleted_at AND ($unpublished OR posted <= time::now()) \
+                    ORDER BY posted DESC"))
+            .bind(("user", user_id))
+            .bind(("unpublished", include_unpublished))
+            .await?
+            .take(0)?;
+
+        Ok(posts)
+    }
+
+    /// Creates a post credited to `authors`, or to `editor` if no authors are given
+    ///
+    /// The post and its authors are written in one transaction, so there are no posts without authors.
+    #[instrument(skip_all)]
+    pub async fn add_post(&self, post: BodyPost, editor: &User) -> surrealdb::Result<Option<Post>> {
+        let _timer = time_query("add_post");
+        // Chosen up front, the transaction needs it to relate the authors
+        let id = Id::rand().to_raw();
+        let authors = post.authors.unwrap_or_else(|| vec![editor.id.to_string()]);
+
+        self.database
+            .query(format!("BEGIN TRANSACTION; \
+                    LET $post = type::thing(\"post\", $id); \
+                    CREATE $post CONTENT $content; \
+                    {SET_AUTHORS} \
+                    COMMIT TRANSACTION;"))
+            .bind(("id", id.clone()))
+            .bind(("content", NewPost {
+                title: post.title.unwrap_or_default(),
+                summary: post.summary,
+                content: post.content,
+                tags: post.tags.unwrap_or_default(),
+                project: post.project,
+                likes: post.likes.unwrap_or(0),
+                views: post.views.unwrap_or(0),
+                posted: post.posted.unwrap_or_else(|| Datetime::from(Utc::now())),
+                editor: RecordId::from_table_key("user", editor.id.to_string()),
+            }))
+            .bind(("authors", post_authors(authors)))
+            .await?
+            .check()?;
+
+        self.fetch_post(id).await
+    }
+
+    /// The ids out of `ids` which belong to users, users in the trash don't count
+    #[instrument(skip_all)]
+    pub async fn fetch_existing_user_ids(&self, ids: Vec<String>) -> surrealdb::Result<Vec<String>> {
+        let _timer = time_query("fetch_existing_user_ids");
+        let ids: Vec<RecordId> = ids.into_iter().map(|id| RecordId::from_table_key("user", id)).collect();
+        let existing: Vec<String> = self.database
+            .query("SELECT VALUE <string> record::id(id) FROM $ids WHERE !deleted_at")
+            .bind(("ids", ids))
+            .await?
+            .take(0)?;
+
+        Ok(existing)
+    }
+
+    /// Uses up every reset token issued so far, even when the password stayed the same
+    #[instrument(skip_all)]
+    pub async fn mark_password_changed(&self, user_id: String) -> surrealdb::Result<()> {
+        let _timer = time_query("mark_password_changed");
+        self.database
+            .query("UPDATE type::thing(\"user\", $id) SET password_changed = time::now()")
+            .bind(("id", user_id))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
     pub async fn update_user(&self, user: &User) -> surrealdb::Result<Option<User>> {
+        let _timer = time_query("update_user");
         self.database
             .update(("user", user.id.to_string()))
             .merge(BodyUser {
@@ -119,10 +1198,91 @@ impl DatabaseManager {
                 name: Some(user.name.clone()),
                 admin: Some(user.admin),
                 email: Some(user.email.clone()),
+                email_verified: Some(user.email_verified),
                 password: Some(user.password.clone()),
                 firstname: user.firstname.clone(),
-                lastname: user.lastname.clone()
+                lastname: user.lastname.clone(),
+                display_name: user.display_name.clone(),
+                bio: user.bio.clone(),
+                links: Some(user.links.clone()),
+                visibility: Some(user.visibility.clone()),
             })
             .await
     }
-}
\ No newline at end of file
+
+    /// Fetches the ids of every record in `table`, including the ones in the trash
+    #[instrument(skip_all)]
+    pub async fn fetch_record_ids(&self, table: &str) -> surrealdb::Result<Vec<String>> {
+        let _timer = time_query("fetch_record_ids");
+        let ids: Vec<String> = self.database
+            .query("SELECT VALUE <string> record::id(id) FROM type::table($table)")
+            .bind(("table", table.to_string()))
+            .await?
+            .take(0)?;
+
+        Ok(ids)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn add_audit_entry(&self, actor: &User, action: &str, target: &str, before: Option<serde_json::Value>, after: Option<serde_json::Value>, ip: Option<String>) -> surrealdb::Result<()> {
+        let _timer = time_query("add_audit_entry");
+        let _: Option<AuditEntry> = self.database
+            .create("audit")
+            .content(NewAuditEntry {
+                actor: RecordId::from_table_key("user", actor.id.to_string()),
+                action: action.to_string(),
+                target: target.to_string(),
+                before,
+                after,
+                ip,
+                created: Datetime::from(Utc::now()),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fetches the audit log, newest entries first. Every filter is optional.
+    #[instrument(skip_all)]
+    pub async fn fetch_audit_entries(&self, actor: Option<String>, action: Option<String>, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, pagination: &PaginationParams) -> surrealdb::Result<Vec<AuditEntry>> {
+        let _timer = time_query("fetch_audit_entries");
+        let entries: Vec<AuditEntry> = self.database
+            .query("SELECT * FROM audit \
+                    WHERE (!$actor OR actor = type::thing(\"user\", $actor)) AND (!$action OR action = $action) \
+                    AND (!$since OR created >= $since) AND (!$until OR created <= $until) \
+                    ORDER BY created DESC LIMIT $limit START $start")
+            .bind(("actor", actor))
+            .bind(("action", action))
+            .bind(("since", since.map(Datetime::from)))
+            .bind(("until", until.map(Datetime::from)))
+            .bind(("limit", pagination.limit()))
+            .bind(("start", pagination.start()))
+            .await?
+            .take(0)?;
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::passwords::hash_password;
+    use super::*;
+
+    #[actix_web::test]
+    async fn password_migration_skips_hashes() {
+        let migration = DATABASE_DEFINITIONS.lines()
+            .find(|line| line.contains("crypto::argon2::generate(password)"))
+            .expect("the password migration is missing");
+
+        // Hashing a hash again would lock everyone out, so the migration has to recognize what the api writes
+        let prefix = migration.split("string::starts_with(password, \"").nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("the migration doesn't skip hashed passwords");
+        assert_eq!(prefix, "$argon2");
+
+        let hash = hash_password("secret".to_string()).await.unwrap();
+        assert!(hash.starts_with(prefix));
+        assert!(!"secret".starts_with(prefix));
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tracing::error;
+use crate::error::ApiError;
+use crate::storage::database_manager::{DatabaseManager, PurgedTrash};
+use crate::storage::storage_manager::StorageManager;
+
+// Directories in the storage whose files are named after the id of the record owning them
+const OWNED_DIRECTORIES: [(&str, &str); 2] = [
+    ("userimages", "user"),
+    ("postimages", "post"),
+];
+
+/// Removes the files belonging to records which were purged from the database
+///
+/// A file which can't be removed is only logged, the orphan scan picks it up later.
+pub async fn remove_purged_objects(storage_manager: &StorageManager, purged: &PurgedTrash) {
+    let paths = purged.users.iter().map(|user| format!("userimages/{}", user.id))
+        .chain(purged.posts.iter().map(|post| format!("postimages/{}", post.id)));
+
+    for path in paths {
+        if let Err(err) = storage_manager.delete(&PathBuf::from(&path)).await {
+            error!("Couldn't remove {} from the storage: {}", path, err);
+        }
+    }
+}
+
+/// Lists the files in the storage whose owning record doesn't exist anymore
+///
+/// Records in the trash still own their files, they could be restored.
+pub async fn find_orphans(database_manager: &DatabaseManager, storage_manager: &StorageManager) -> Result<Vec<String>, ApiError> {
+    let mut orphans = Vec::new();
+
+    for (directory, table) in OWNED_DIRECTORIES {
+        let files = match storage_manager.get_files(&PathBuf::from(directory)).await? {
+            Some(files) => files,
+            None => continue,
+        };
+
+        let ids: HashSet<String> = database_manager.fetch_record_ids(table).await?.into_iter().collect();
+
+        orphans.extend(files.into_iter()
+            .filter(|file| !ids.contains(file))
+            .map(|file| format!("{}/{}", directory, file)));
+    }
+
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Deletes every orphaned file and returns the ones which were removed
+pub async fn remove_orphans(database_manager: &DatabaseManager, storage_manager: &StorageManager) -> Result<Vec<String>, ApiError> {
+    let orphans = find_orphans(database_manager, storage_manager).await?;
+
+    for orphan in &orphans {
+        storage_manager.delete(&PathBuf::from(orphan)).await?;
+    }
+
+    Ok(orphans)
+}
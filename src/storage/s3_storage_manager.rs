@@ -2,8 +2,8 @@ use std::env;
 use std::error::Error;
 use async_trait::async_trait; // For async trait methods
 use bytes::Bytes;
-use std::path::PathBuf;
-use log::info;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
 use tokio::fs;
 use crate::storage::storage_manager::StorageTrait; // Use tokio::fs
 
@@ -23,7 +23,7 @@ impl S3SystemStorage {
 
 #[async_trait]
 impl StorageTrait for S3SystemStorage {
-    async fn get(&self, path: &PathBuf) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+    async fn get(&self, path: &Path) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
         let full_path = self.base_dir.join(path);
         if let Ok(data) = fs::read(full_path).await {
             Ok(Some(Bytes::from(data)))
@@ -32,8 +32,8 @@ impl StorageTrait for S3SystemStorage {
         }
     }
 
-    async fn put(&self, path: &PathBuf, data: &Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
-        info!("Writing {}", path.display());
+    async fn put(&self, path: &Path, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        debug!("Writing {}", path.display());
         let full_path = self.base_dir.join(path);
         if let Some(parent_dir) = full_path.parent() {
             fs::create_dir_all(parent_dir).await?; // Create directories if not present
@@ -42,31 +42,42 @@ impl StorageTrait for S3SystemStorage {
         Ok(())
     }
 
-    async fn delete(&self, path: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn delete(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
         let full_path = self.base_dir.join(path);
-        fs::remove_file(full_path).await?;
-        Ok(())
+        match fs::remove_file(full_path).await {
+            // Already gone, which is what the caller wanted
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
     }
 
-    async fn size(&self, path: &PathBuf) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    async fn size(&self, path: &Path) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
         let mut total_size: u64 = 0;
         let full_path = self.base_dir.join(path);
 
-        fs::create_dir_all(&full_path).await?; // Create directories if not present
+        let metadata = match fs::metadata(&full_path).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if metadata.is_file() {
+            return Ok(Some(metadata.len()));
+        }
+
         let mut entries = fs::read_dir(full_path).await?;
         while let Some(entry) = entries.next_entry().await? {
             let metadata = entry.metadata().await?;
             if metadata.is_file() {
                 total_size += metadata.len();
             } else if metadata.is_dir() {
-                total_size += self.size(&entry.path()).await?.unwrap();
+                total_size += self.size(&entry.path()).await?.unwrap_or_default();
             }
         }
 
         Ok(Some(total_size))
     }
 
-    async fn get_files(&self, path: &PathBuf) -> Result<Option<Vec<String>>, Box<dyn Error + Send + Sync>> {
+    async fn get_files(&self, path: &Path) -> Result<Option<Vec<String>>, Box<dyn Error + Send + Sync>> {
         let mut files: Vec<String> = Vec::new();
         let full_path = self.base_dir.join(path);
 
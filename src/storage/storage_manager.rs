@@ -1,17 +1,22 @@
 use async_trait::async_trait; // For async trait methods
 use bytes::Bytes; // For reading/writing byte streams
-use std::path::PathBuf; // For file paths
+use std::path::{Component, Path}; // For file paths
 use std::sync::Arc;
+use tracing::instrument;
+use crate::metrics::count_storage_operation;
 
 #[async_trait]
 pub trait StorageTrait: Send + Sync {
-    async fn get(&self, path: &PathBuf) -> Result<Option<Bytes>, Box<dyn std::error::Error + Send + Sync>>;
-    async fn put(&self, path: &PathBuf, data: &Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn delete(&self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn size(&self, path: &PathBuf) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>>;
-    async fn get_files(&self, path: &PathBuf) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>>;
+    async fn get(&self, path: &Path) -> Result<Option<Bytes>, Box<dyn std::error::Error + Send + Sync>>;
+    async fn put(&self, path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    async fn delete(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    async fn size(&self, path: &Path) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>>;
+    async fn get_files(&self, path: &Path) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>>;
 }
 
+// Written and removed again by the readiness check
+const WRITE_PROBE: &str = ".write-probe";
+
 #[derive(Clone)]
 pub struct StorageManager {
     storage: Arc<dyn StorageTrait>, // Dynamically dispatched storage implementation
@@ -24,29 +29,72 @@ impl StorageManager {
 
     /// Retrieve a file from the given location
     ///
-    /// `path` can't be absolute or it will override the base path.
-    pub async fn get(&self, path: &PathBuf) -> Result<Option<Bytes>, Box<dyn std::error::Error + Send + Sync>> {
-        self.storage.get(path).await
+    /// `path` has to be relative and can't contain `..`, other paths are refused.
+    #[instrument(skip_all, fields(path = %path.display()))]
+    pub async fn get(&self, path: &Path) -> Result<Option<Bytes>, Box<dyn std::error::Error + Send + Sync>> {
+        check_path(path)?;
+        let result = self.storage.get(path).await;
+        count_storage_operation("get", &result);
+        result
     }
 
     /// Writes a file to the given location
     ///
-    /// `path` can't be absolute or it will override the base path.
-    pub async fn put(&self, path: &PathBuf, data: &Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.storage.put(path, data).await
+    /// `path` has to be relative and can't contain `..`, other paths are refused.
+    #[instrument(skip_all, fields(path = %path.display(), bytes = data.len()))]
+    pub async fn put(&self, path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        check_path(path)?;
+        let result = self.storage.put(path, data).await;
+        count_storage_operation("put", &result);
+        result
     }
 
-    /// Deletes a file at the given location
+    /// Deletes a file at the given location, deleting a file which doesn't exist is not an error
     ///
-    /// `path` can't be absolute or it will override the base path.
-    pub async fn delete(&self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.storage.delete(path).await
+    /// `path` has to be relative and can't contain `..`, other paths are refused.
+    #[instrument(skip_all, fields(path = %path.display()))]
+    pub async fn delete(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        check_path(path)?;
+        let result = self.storage.delete(path).await;
+        count_storage_operation("delete", &result);
+        result
+    }
+
+    /// The size of a file or of everything in a directory, `None` if nothing is at the given location
+    #[instrument(skip_all, fields(path = %path.display()))]
+    pub async fn size(&self, path: &Path) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        check_path(path)?;
+        let result = self.storage.size(path).await;
+        count_storage_operation("size", &result);
+        result
     }
 
-    pub async fn size(&self, path: &PathBuf) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
-        self.storage.size(path).await
+    /// Lists the names of the files in the given directory, `None` if the directory doesn't exist
+    #[instrument(skip_all, fields(path = %path.display()))]
+    pub async fn get_files(&self, path: &Path) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+        check_path(path)?;
+        let result = self.storage.get_files(path).await;
+        count_storage_operation("get_files", &result);
+        result
     }
 
-    pub async fn get_files(&self, path: &PathBuf) -> Result<Option<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
-        self.storage.get_files(path).await
-    }}
+    /// Whether files can be written, by writing and deleting a probe file
+    pub async fn check_writable(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let probe = Path::new(WRITE_PROBE);
+        self.put(probe, b"ok").await?;
+        self.delete(probe).await
+    }
+}
+
+// Paths come from record ids and other input, they must not be able to leave the base directory of the storage
+fn check_path(path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let is_plain = path.components().next().is_some() && path.components().all(|component| match component {
+        Component::Normal(part) => !part.to_string_lossy().contains('\\'),
+        _ => false,
+    });
+
+    if !is_plain {
+        return Err(format!("invalid storage path {}", path.display()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+use chrono::Utc;
+use tracing::{error, info};
+use crate::storage::database_manager::DatabaseManager;
+use crate::storage::reconcile::remove_purged_objects;
+use crate::storage::storage_manager::StorageManager;
+
+// How often the trash is checked for records which are due to be purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Permanently deletes users and posts once they have been in the trash for `retention`, together with their files
+///
+/// Runs until the server shuts down, the first purge happens right away.
+pub async fn purge_trash_periodically(database_manager: DatabaseManager, storage_manager: StorageManager, retention: chrono::Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Tried again with the next tick
+        if !database_manager.is_available() {
+            continue;
+        }
+
+        match database_manager.purge_trash(Utc::now() - retention).await {
+            Ok(purged) => {
+                remove_purged_objects(&storage_manager, &purged).await;
+
+                if !purged.users.is_empty() || !purged.posts.is_empty() {
+                    info!("Purged {} users and {} posts from the trash", purged.users.len(), purged.posts.len());
+                }
+            }
+            Err(err) => error!("Couldn't purge the trash: {}", err),
+        }
+
+        // Expired sessions can't be used anymore, they would only pile up
+        if let Err(err) = database_manager.delete_expired_sessions().await {
+            error!("Couldn't remove the expired sessions: {}", err);
+        }
+    }
+}
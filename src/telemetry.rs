@@ -0,0 +1,55 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+use crate::config::{LogFormat, ServerConfig, TracingConfig};
+
+/// Sets up logging and, if a collector is configured, the export of spans
+///
+/// Every log line carries the spans it was written in, the request span holds the request id. Lines written
+/// through the `log` crate, e.g. by actix, end up in the same output. The returned provider has to be shut down
+/// before exiting, otherwise the last spans are lost.
+pub fn init(server: &ServerConfig, tracing: &TracingConfig) -> Result<Option<SdkTracerProvider>, String> {
+    let filter = EnvFilter::try_new(&server.log_level)
+        .map_err(|err| format!("invalid log level {}: {}", server.log_level, err))?;
+
+    let output = match server.log_format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        LogFormat::Text => fmt::layer().boxed(),
+    };
+
+    let provider = match &tracing.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .build()
+                .map_err(|err| format!("couldn't set up the otlp exporter: {err}"))?;
+
+            Some(SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(tracing.service_name.clone()).build())
+                .build())
+        }
+        None => None,
+    };
+
+    let spans = provider.as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(spans)
+        .try_init()
+        .map_err(|err| format!("couldn't set up logging: {err}"))?;
+
+    Ok(provider)
+}
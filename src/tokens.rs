@@ -0,0 +1,226 @@
+use actix_web::{delete, get, HttpResponse, post, Scope, web};
+use actix_web::http::Method;
+use actix_web::web::Json;
+use chrono::Utc;
+use tracing::error;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::{OpenApi, ToSchema};
+use crate::audit::{Auditor, snapshot};
+use crate::definitions::{ApiToken, BodyApiToken, User};
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+// Tells personal access tokens apart from login tokens, and makes them easy to find for secret scanners
+const TOKEN_PREFIX: &str = "ihp_";
+const TOKEN_LENGTH: usize = 40;
+// How much of the token is stored in plain text, to recognize it in the list of tokens
+const SHOWN_PREFIX_LENGTH: usize = TOKEN_PREFIX.len() + 4;
+
+/// The scopes a personal access token can have
+///
+/// A token is only accepted below `/api/v1/{resource}` if it has the scope `{resource}:read` for GET requests
+/// or `{resource}:write` for everything else. Tokens can't be used for anything below `/api/v1/auth` and can't
+/// change the email or the password of a user, so a leaked token can't mint new tokens or change how its user logs in.
+/// The permissions of the user still apply on top of that.
+pub const TOKEN_SCOPES: [&str; 11] = [
+    "posts:read",
+    "posts:write",
+    "users:read",
+    "users:write",
+    "invites:read",
+    "invites:write",
+    "audit:read",
+    "lockouts:read",
+    "lockouts:write",
+    "storage:read",
+    "storage:write",
+];
+
+// Only returned once, when the token is created
+#[derive(Serialize, ToSchema)]
+struct CreatedToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    secret: String,
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Finds the user of a personal access token, if the token may be used for `method` on `path`
+pub async fn authenticate(database_manager: &DatabaseManager, token: &str, method: &Method, path: &str) -> Result<User, ApiError> {
+    let api_token = database_manager.fetch_api_token(hash_token(token)).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    let required_scope = required_scope(method, path).ok_or(ApiError::PermissionDenied)?;
+    if !api_token.scopes.contains(&required_scope) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    // The user might have been deleted in the meantime
+    let user = database_manager.fetch_user(api_token.user.to_string()).await?
+        .ok_or(ApiError::InvalidToken)?;
+
+    if let Err(err) = database_manager.touch_api_token(api_token.id.to_string()).await {
+        error!("Couldn't update the last use of token {}: {}", api_token.id, err);
+    }
+
+    Ok(user)
+}
+
+fn required_scope(method: &Method, path: &str) -> Option<String> {
+    let resource = path.strip_prefix("/api/v1/")?.split('/').next()?;
+    let access = if method == Method::GET || method == Method::HEAD { "read" } else { "write" };
+    let scope = format!("{}:{}", resource, access);
+
+    TOKEN_SCOPES.contains(&scope.as_str()).then_some(scope)
+}
+
+fn check_token(token: &BodyApiToken) -> Result<(), ApiError> {
+    if token.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("A token needs a name".to_string()));
+    }
+    if token.scopes.is_empty() {
+        return Err(ApiError::BadRequest("A token needs at least one scope".to_string()));
+    }
+    if let Some(scope) = token.scopes.iter().find(|scope| !TOKEN_SCOPES.contains(&scope.as_str())) {
+        return Err(ApiError::BadRequest(format!("Unknown scope {}", scope)));
+    }
+    // Such a token could never be used
+    if token.expires.is_some_and(|expires| expires <= Utc::now()) {
+        return Err(ApiError::BadRequest("A token has to expire in the future".to_string()));
+    }
+
+    Ok(())
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(tokens_get, token_post, token_delete))]
+pub(crate) struct TokenApi;
+
+// Registered before the auth service, otherwise its scope would swallow these routes
+pub fn token_service() -> Scope {
+    web::scope("/api/v1/auth/tokens")
+        .service(tokens_get)
+        .service(token_post)
+        .service(token_delete)
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200, body = Vec<ApiToken>)), security(("bearer" = [])))]
+#[get("")]
+async fn tokens_get(
+    user: User,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let tokens = database_manager.fetch_api_tokens(user.id.to_string()).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[utoipa::path(tag = "auth", request_body = BodyApiToken, responses((status = 201, body = CreatedToken)), security(("bearer" = [])))]
+#[post("")]
+async fn token_post(
+    user: User,
+    body: Json<BodyApiToken>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let mut token = body.into_inner();
+    check_token(&token)?;
+
+    token.scopes.sort();
+    token.scopes.dedup();
+
+    let secret: String = TOKEN_PREFIX.chars()
+        .chain(rand::rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from))
+        .collect();
+
+    let created_token = database_manager.add_api_token(token, hash_token(&secret), secret[..SHOWN_PREFIX_LENGTH].to_string(), &user).await?
+        .ok_or(ApiError::Internal("the token wasn't created".to_string()))?;
+    auditor.record(&user, "token.create", format!("api_token:{}", created_token.id), None, snapshot(&created_token)).await;
+
+    Ok(HttpResponse::Created().json(CreatedToken { token: created_token, secret }))
+}
+
+#[utoipa::path(tag = "auth", responses((status = 200)), security(("bearer" = [])))]
+#[delete("/{tokenId}")]
+async fn token_delete(
+    user: User,
+    path: web::Path<String>,
+    auditor: Auditor,
+    database_manager: web::Data<DatabaseManager>) -> Result<HttpResponse, ApiError> {
+
+    let token_id = path.into_inner();
+
+    let deleted_token = database_manager.delete_api_token(user.id.to_string(), token_id).await?
+        .ok_or(ApiError::NotFound)?;
+    auditor.record(&user, "token.delete", format!("api_token:{}", deleted_token.id), snapshot(&deleted_token), None).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use super::*;
+
+    #[test]
+    fn reading_needs_read_scope() {
+        assert_eq!(required_scope(&Method::GET, "/api/v1/posts/abc"), Some("posts:read".to_string()));
+        assert_eq!(required_scope(&Method::HEAD, "/api/v1/users"), Some("users:read".to_string()));
+    }
+
+    #[test]
+    fn changing_needs_write_scope() {
+        assert_eq!(required_scope(&Method::POST, "/api/v1/posts"), Some("posts:write".to_string()));
+        assert_eq!(required_scope(&Method::PATCH, "/api/v1/users/abc"), Some("users:write".to_string()));
+        assert_eq!(required_scope(&Method::DELETE, "/api/v1/invites/abc"), Some("invites:write".to_string()));
+    }
+
+    #[test]
+    fn auth_routes_are_refused() {
+        assert_eq!(required_scope(&Method::GET, "/api/v1/auth/me"), None);
+        assert_eq!(required_scope(&Method::GET, "/api/v1/auth/tokens"), None);
+        assert_eq!(required_scope(&Method::POST, "/api/v1/auth/tokens"), None);
+    }
+
+    fn token(scopes: &[&str], expires: Option<DateTime<Utc>>) -> BodyApiToken {
+        BodyApiToken {
+            name: "ci".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        assert!(check_token(&token(&["posts:read"], None)).is_ok());
+        assert!(check_token(&token(&["posts:read"], Some(Utc::now() + Duration::days(1)))).is_ok());
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        assert!(matches!(check_token(&token(&["posts:read"], Some(Utc::now() - Duration::days(1)))), Err(ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn unknown_scopes_are_refused() {
+        assert!(matches!(check_token(&token(&["posts:delete"], None)), Err(ApiError::BadRequest(_))));
+        assert!(matches!(check_token(&token(&[], None)), Err(ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn unknown_routes_are_refused() {
+        assert_eq!(required_scope(&Method::POST, "/api/v1/audit"), None);
+        assert_eq!(required_scope(&Method::GET, "/api/v1/unknown"), None);
+        assert_eq!(required_scope(&Method::GET, "/feed.rss"), None);
+        assert_eq!(required_scope(&Method::GET, "/api/v1posts"), None);
+    }
+}
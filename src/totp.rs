@@ -0,0 +1,94 @@
+use std::time::SystemTime;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::definitions::User;
+use crate::error::ApiError;
+use crate::storage::database_manager::DatabaseManager;
+
+// The defaults every authenticator app understands
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+// Codes of the previous and the next step are accepted too, clocks are never perfectly in sync
+const SKEW: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// Generates a new base32 encoded secret
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+/// The `otpauth://` URI authenticator apps are set up with, usually shown as a QR code
+pub fn otpauth_uri(secret: &str, issuer: &str, account: &str) -> Result<String, ApiError> {
+    // Neither of them may contain a colon, it separates them in the label of the URI
+    let totp = totp(secret, Some(issuer.replace(':', "")), account.replace(':', ""))?;
+    Ok(totp.get_url())
+}
+
+/// Checks `code` against `secret` and returns the time step it belongs to if it is valid
+pub fn verify_code(secret: &str, code: &str) -> Result<Option<i64>, ApiError> {
+    let totp = totp(secret, None, String::new())?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let current_step = now / STEP as i64;
+
+    let step = (current_step - SKEW..=current_step + SKEW)
+        .find(|step| totp.check(code, (*step as u64) * STEP));
+
+    Ok(step)
+}
+
+/// Checks the second factor of `user`, which is either a code of their authenticator or one of their recovery codes
+///
+/// Both can only be used once, a used recovery code is removed and codes of a step up to the last used one are rejected.
+pub async fn verify_second_factor(database_manager: &DatabaseManager, user: &User, code: &str) -> Result<bool, ApiError> {
+    let secret = match &user.totp_secret {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+
+    let code = code.trim();
+
+    if let Some(step) = verify_code(secret, code)? {
+        return Ok(database_manager.use_totp_step(user.id.to_string(), step).await?);
+    }
+
+    Ok(database_manager.use_recovery_code(user.id.to_string(), hash_recovery_code(code)).await?)
+}
+
+/// Generates a new set of recovery codes, these are only ever shown to the user once
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::rng()
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LENGTH)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+
+            format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+        })
+        .collect()
+}
+
+/// Hashes a recovery code the way it is stored in the database
+///
+/// The codes are random enough that a fast hash is fine. Case and dashes are ignored, people type these by hand.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+fn totp(secret: &str, issuer: Option<String>, account: String) -> Result<TOTP, ApiError> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()
+        .map_err(|err| ApiError::Internal(format!("invalid totp secret: {err}")))?;
+
+    TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP, secret, issuer, account)
+        .map_err(|err| ApiError::Internal(format!("invalid totp parameters: {err}")))
+}